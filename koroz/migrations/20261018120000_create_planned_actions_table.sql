@@ -0,0 +1,10 @@
+-- Actions the purger would have taken in dry-run mode
+
+CREATE TABLE planned_actions (
+    id BIGSERIAL PRIMARY KEY,
+    planned_at TIMESTAMPTZ NOT NULL,
+    action VARCHAR NOT NULL,
+    target VARCHAR NOT NULL,
+    domain_name VARCHAR NOT NULL,
+    record_type VARCHAR NOT NULL
+);
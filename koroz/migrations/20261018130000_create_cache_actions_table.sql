@@ -0,0 +1,18 @@
+-- Audit trail of every invalidation and repopulation
+
+CREATE TABLE cache_actions (
+    id BIGSERIAL PRIMARY KEY,
+    at TIMESTAMPTZ NOT NULL,
+    domain_name VARCHAR NOT NULL,
+    record_type VARCHAR NOT NULL,
+    action VARCHAR NOT NULL,
+    backend VARCHAR NOT NULL,
+    target VARCHAR NOT NULL,
+    trigger VARCHAR NOT NULL,
+    duration_ms DOUBLE PRECISION NOT NULL,
+    outcome VARCHAR NOT NULL,
+    exit_code INTEGER,
+    error VARCHAR
+);
+
+CREATE INDEX cache_actions_domain_name_at ON cache_actions (domain_name, at);
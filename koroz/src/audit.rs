@@ -0,0 +1,144 @@
+use std::time::Duration;
+
+use anyhow::Context as _;
+use chrono::{DateTime, Utc};
+use log::{error, info};
+use serde::Serialize;
+use sqlx::{Pool, Postgres};
+use tokio::{fs::OpenOptions, io::AsyncWriteExt, sync::mpsc};
+
+use crate::{
+    event_manip::BackendError,
+    settings::AuditSettings,
+    structs::{RRsetKey, RecordType},
+};
+
+/// What made koroz act on a record.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Trigger {
+    /// The record was about to expire.
+    Ttl,
+    /// A policy forced the refresh or moved its deadline.
+    Policy,
+    /// Someone asked for it through the HTTP API.
+    Api,
+}
+
+impl Trigger {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Trigger::Ttl => "ttl",
+            Trigger::Policy => "policy",
+            Trigger::Api => "api",
+        }
+    }
+}
+
+/// One backend action on one RRset.
+#[derive(Debug, Clone)]
+pub struct ActionReport<'a> {
+    pub action: &'a str,
+    pub backend: &'a str,
+    pub target: &'a str,
+    pub rrset: &'a RRsetKey,
+    pub trigger: Trigger,
+    /// For batched actions, the time the whole batch took.
+    pub elapsed: Duration,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditEntry {
+    pub timestamp: DateTime<Utc>,
+    pub domain_name: String,
+    pub record_type: RecordType,
+    pub action: String,
+    pub backend: String,
+    pub target: String,
+    pub trigger: Trigger,
+    pub duration_ms: f64,
+    pub outcome: &'static str,
+    pub exit_code: Option<i32>,
+    pub error: Option<String>,
+}
+
+impl AuditEntry {
+    pub fn new<T>(report: &ActionReport, result: &Result<T, BackendError>) -> Self {
+        let (exit_code, error) = match result {
+            Ok(_) => (None, None),
+            Err(BackendError::Exit { code, stderr }) => (*code, Some(stderr.trim().to_string())),
+            Err(e) => (None, Some(e.to_string())),
+        };
+        AuditEntry {
+            timestamp: Utc::now(),
+            domain_name: report.rrset.domain_name.clone(),
+            record_type: report.rrset.record_type.clone(),
+            action: report.action.to_string(),
+            backend: report.backend.to_string(),
+            target: report.target.to_string(),
+            trigger: report.trigger,
+            duration_ms: report.elapsed.as_secs_f64() * 1000.0,
+            outcome: match result {
+                Ok(_) => "success",
+                Err(_) => "failure",
+            },
+            exit_code,
+            error,
+        }
+    }
+}
+
+/// Append-only trail of every cache action. Entries are written by a
+/// background task so that recording one never waits on disk or database.
+#[derive(Debug, Clone, Default)]
+pub struct AuditLog {
+    sender: Option<mpsc::UnboundedSender<AuditEntry>>,
+}
+
+impl AuditLog {
+    pub async fn spawn(settings: &AuditSettings, pool: Pool<Postgres>) -> anyhow::Result<Self> {
+        let pool = settings.store_in_database.then_some(pool);
+        let mut file = match &settings.file {
+            Some(path) => Some(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .await
+                    .with_context(|| format!("failed to open audit log {}", path))?,
+            ),
+            None => None,
+        };
+        if file.is_none() && pool.is_none() {
+            return Ok(AuditLog::default());
+        }
+
+        let (sender, mut receiver) = mpsc::unbounded_channel::<AuditEntry>();
+        tokio::spawn(async move {
+            info!("Started audit log writer");
+            while let Some(entry) = receiver.recv().await {
+                if let Some(file) = file.as_mut() {
+                    let mut line = serde_json::to_string(&entry).unwrap();
+                    line.push('\n');
+                    if let Err(e) = file.write_all(line.as_bytes()).await {
+                        error!("Failed to write audit log entry: {}", e);
+                    }
+                }
+                if let Some(pool) = &pool {
+                    if let Err(e) = entry.insert(pool).await {
+                        error!("Failed to store audit log entry: {:?}", e);
+                    }
+                }
+            }
+        });
+        Ok(AuditLog {
+            sender: Some(sender),
+        })
+    }
+
+    pub fn record<T>(&self, report: &ActionReport, result: &Result<T, BackendError>) {
+        if let Some(sender) = &self.sender {
+            let _ = sender.send(AuditEntry::new(report, result));
+        }
+    }
+}
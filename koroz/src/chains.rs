@@ -0,0 +1,301 @@
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    sync::Arc,
+};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::sync::RwLock;
+
+use crate::{
+    expiry_queue::EVICTION_HEADROOM,
+    structs::{DnsAnswer, RRsetKey, RecordType},
+};
+
+pub type Chains = Arc<RwLock<AliasChains>>;
+
+// Guards against CNAME loops and absurdly long chains in captured traffic.
+const MAX_CHAIN_LENGTH: usize = 16;
+
+/// A resolution path as clients see it: the name that was asked for, every
+/// alias hop, and the type of the RRset found at the end of it.
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub struct AliasChain {
+    pub owner: String,
+    pub links: Vec<String>,
+    pub query_type: RecordType,
+}
+
+impl AliasChain {
+    /// Every RRset the chain is made of: a CNAME per hop plus the final RRset.
+    pub fn records(&self) -> impl Iterator<Item = RRsetKey> + '_ {
+        let last = self.links.len() - 1;
+        self.links
+            .iter()
+            .enumerate()
+            .map(move |(i, name)| RRsetKey {
+                domain_name: name.clone(),
+                record_type: match i == last {
+                    true => self.query_type.clone(),
+                    false => RecordType::CNAME,
+                },
+            })
+    }
+
+    /// The question clients ask to walk this chain.
+    pub fn query(&self) -> RRsetKey {
+        RRsetKey {
+            domain_name: self.owner.clone(),
+            record_type: self.query_type.clone(),
+        }
+    }
+}
+
+/// Alias links learned from captured responses.
+///
+/// Only CNAME is tracked: `dns-parser` rejects packets carrying DNAME records,
+/// so those responses never reach the collector. Resolvers synthesize a CNAME
+/// for every DNAME answer anyway, which is the link that matters for refresh.
+///
+/// Past `max_links`, the links seen least recently are dropped.
+#[derive(Debug)]
+pub struct AliasChains {
+    targets: HashMap<String, String>,
+    owners: HashMap<String, HashSet<String>>,
+    /// Every type asked for through an alias, `www CNAME cdn` is followed
+    /// for both `cdn A` and `cdn AAAA`.
+    query_types: HashMap<String, BTreeSet<RecordType>>,
+    last_seen: HashMap<String, DateTime<Utc>>,
+    max_links: usize,
+}
+
+impl AliasChains {
+    pub fn new(max_links: usize) -> Self {
+        AliasChains {
+            targets: HashMap::new(),
+            owners: HashMap::new(),
+            query_types: HashMap::new(),
+            last_seen: HashMap::new(),
+            max_links,
+        }
+    }
+
+    pub fn record_response(&mut self, answers: &[DnsAnswer]) {
+        let mut response_targets = HashMap::new();
+        for answer in answers {
+            if let Some(target) = &answer.alias_target {
+                let owner = answer.domain_name.to_lowercase();
+                let target = target.to_lowercase();
+                self.link(&owner, &target);
+                self.last_seen
+                    .insert(owner.clone(), answer.read_from_buffer_ts);
+                response_targets.insert(owner, target);
+            }
+        }
+        if self.targets.len() > self.max_links {
+            self.evict_least_recent();
+        }
+
+        // Heads are aliases that nothing else in this response points at; the
+        // type answered at the end of their chain is what clients query for.
+        let pointed_at: HashSet<&String> = response_targets.values().collect();
+        for head in response_targets
+            .keys()
+            .filter(|owner| !pointed_at.contains(owner))
+        {
+            let mut end = head;
+            for _ in 0..MAX_CHAIN_LENGTH {
+                match response_targets.get(end) {
+                    Some(next) => end = next,
+                    None => break,
+                }
+            }
+            let query_type = answers
+                .iter()
+                .find(|answer| {
+                    answer.alias_target.is_none() && answer.domain_name.eq_ignore_ascii_case(end)
+                })
+                .map(|answer| answer.record_type.clone())
+                .unwrap_or(RecordType::CNAME);
+            self.query_types
+                .entry(head.clone())
+                .or_default()
+                .insert(query_type);
+        }
+    }
+
+    fn link(&mut self, owner: &str, target: &str) {
+        if let Some(previous) = self.targets.insert(owner.to_string(), target.to_string()) {
+            if previous != target {
+                self.forget_owner_of(owner, &previous);
+            }
+        }
+        self.owners
+            .entry(target.to_string())
+            .or_default()
+            .insert(owner.to_string());
+    }
+
+    fn forget_owner_of(&mut self, owner: &str, target: &str) {
+        if let Some(owners) = self.owners.get_mut(target) {
+            owners.remove(owner);
+            if owners.is_empty() {
+                self.owners.remove(target);
+            }
+        }
+    }
+
+    fn unlink(&mut self, owner: &str) {
+        if let Some(target) = self.targets.remove(owner) {
+            self.forget_owner_of(owner, &target);
+        }
+        self.query_types.remove(owner);
+        self.last_seen.remove(owner);
+    }
+
+    fn evict_least_recent(&mut self) {
+        let keep = (self.max_links as f64 * EVICTION_HEADROOM) as usize;
+        let mut candidates: Vec<(DateTime<Utc>, String)> = self
+            .last_seen
+            .iter()
+            .map(|(owner, seen)| (*seen, owner.clone()))
+            .collect();
+        candidates.sort();
+        let evicted = candidates.len().saturating_sub(keep);
+        for (_, owner) in candidates.into_iter().take(evicted) {
+            self.unlink(&owner);
+        }
+    }
+
+    fn heads(&self, name: &str) -> HashSet<String> {
+        let mut heads = HashSet::new();
+        let mut pending = vec![(name.to_string(), 0)];
+        let mut visited = HashSet::new();
+        while let Some((current, depth)) = pending.pop() {
+            if !visited.insert(current.clone()) {
+                continue;
+            }
+            match self.owners.get(&current) {
+                Some(owners) if depth < MAX_CHAIN_LENGTH => {
+                    pending.extend(owners.iter().map(|owner| (owner.clone(), depth + 1)))
+                }
+                _ => {
+                    heads.insert(current);
+                }
+            }
+        }
+        heads
+    }
+
+    /// One chain per type queried through `owner`.
+    fn chains_from(&self, owner: &str) -> Vec<AliasChain> {
+        let mut links = vec![owner.to_string()];
+        while let Some(target) = self.targets.get(links.last().unwrap()) {
+            if links.len() == MAX_CHAIN_LENGTH || links.contains(target) {
+                break;
+            }
+            links.push(target.clone());
+        }
+        let query_types = match self.query_types.get(owner) {
+            Some(query_types) if !query_types.is_empty() => query_types.clone(),
+            _ => BTreeSet::from([RecordType::CNAME]),
+        };
+        query_types
+            .into_iter()
+            .map(|query_type| AliasChain {
+                owner: owner.to_string(),
+                links: links.clone(),
+                query_type,
+            })
+            .collect()
+    }
+
+    /// Chains `name` takes part in, either as an alias or as a target.
+    /// Empty when the name was never seen in a CNAME answer.
+    pub fn chains_through(&self, name: &str) -> Vec<AliasChain> {
+        let name = name.to_lowercase();
+        if !self.targets.contains_key(&name) && !self.owners.contains_key(&name) {
+            return vec![];
+        }
+        self.heads(&name)
+            .iter()
+            .flat_map(|head| self.chains_from(head))
+            .collect()
+    }
+
+    /// Chains `rrset` is one of the records of, those a refresh of it has to
+    /// go through. `cdn A` is not part of the chain ending in `cdn AAAA`.
+    pub fn chains_with(&self, rrset: &RRsetKey) -> Vec<AliasChain> {
+        self.chains_through(&rrset.domain_name)
+            .into_iter()
+            .filter(|chain| {
+                chain.records().any(|record| {
+                    record.record_type == rrset.record_type
+                        && record.domain_name.eq_ignore_ascii_case(&rrset.domain_name)
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::structs::Cls;
+
+    fn answer(domain_name: &str, record_type: RecordType, alias_target: Option<&str>) -> DnsAnswer {
+        DnsAnswer {
+            domain_name: domain_name.to_string(),
+            ttl: 300,
+            cls: Cls::IN,
+            record_type,
+            read_from_buffer_ts: Utc.timestamp_opt(1_700_000_000, 0).unwrap(),
+            alias_target: alias_target.map(str::to_string),
+            source_address: None,
+            destination_address: None,
+        }
+    }
+
+    fn rrset(domain_name: &str, record_type: RecordType) -> RRsetKey {
+        RRsetKey {
+            domain_name: domain_name.to_string(),
+            record_type,
+        }
+    }
+
+    #[test]
+    fn an_alias_queried_for_two_types_keeps_a_chain_per_type() {
+        let mut chains = AliasChains::new(100);
+        chains.record_response(&[
+            answer("www", RecordType::CNAME, Some("cdn")),
+            answer("cdn", RecordType::A, None),
+        ]);
+        chains.record_response(&[
+            answer("www", RecordType::CNAME, Some("cdn")),
+            answer("cdn", RecordType::AAAA, None),
+        ]);
+
+        let queries = |due: RRsetKey| -> Vec<RRsetKey> {
+            chains
+                .chains_with(&due)
+                .iter()
+                .map(AliasChain::query)
+                .collect()
+        };
+        assert_eq!(
+            queries(rrset("cdn", RecordType::A)),
+            [rrset("www", RecordType::A)]
+        );
+        assert_eq!(
+            queries(rrset("cdn", RecordType::AAAA)),
+            [rrset("www", RecordType::AAAA)]
+        );
+        assert_eq!(
+            queries(rrset("www", RecordType::CNAME)),
+            [rrset("www", RecordType::A), rrset("www", RecordType::AAAA)]
+        );
+        assert!(queries(rrset("cdn", RecordType::MX)).is_empty());
+    }
+}
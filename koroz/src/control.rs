@@ -0,0 +1,144 @@
+use std::sync::Arc;
+
+use log::info;
+use serde::Serialize;
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::watch,
+};
+
+use crate::PURGER_STATE;
+
+pub type PurgerControl = Arc<Control>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PurgerState {
+    Running,
+    /// No new actions. Records the current cycle already flushed are still
+    /// re-queried, the rest of them go back in the queue.
+    Paused,
+    /// The current cycle finishes, no new one starts.
+    Draining,
+    /// Nothing in flight any more.
+    Drained,
+}
+
+impl PurgerState {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            PurgerState::Running => "running",
+            PurgerState::Paused => "paused",
+            PurgerState::Draining => "draining",
+            PurgerState::Drained => "drained",
+        }
+    }
+
+    /// Value exported on the `purger_state` gauge.
+    pub fn gauge_value(&self) -> i64 {
+        match self {
+            PurgerState::Running => 0,
+            PurgerState::Paused => 1,
+            PurgerState::Draining => 2,
+            PurgerState::Drained => 3,
+        }
+    }
+}
+
+/// Whether the purger may act on resolvers. Capture and persistence carry on
+/// whatever the state, so nothing collected meanwhile is lost.
+#[derive(Debug)]
+pub struct Control {
+    state: watch::Sender<PurgerState>,
+}
+
+impl Default for Control {
+    fn default() -> Self {
+        PURGER_STATE.set(PurgerState::Running.gauge_value());
+        Control {
+            state: watch::Sender::new(PurgerState::Running),
+        }
+    }
+}
+
+impl Control {
+    pub fn state(&self) -> PurgerState {
+        *self.state.borrow()
+    }
+
+    fn set(&self, state: PurgerState) {
+        let changed = self.state.send_if_modified(|current| {
+            let changed = *current != state;
+            *current = state;
+            changed
+        });
+        if changed {
+            info!("Purger {}", state.as_str());
+            PURGER_STATE.set(state.gauge_value());
+        }
+    }
+
+    pub fn pause(&self) {
+        self.set(PurgerState::Paused);
+    }
+
+    pub fn resume(&self) {
+        self.set(PurgerState::Running);
+    }
+
+    /// Already drained purgers stay drained.
+    pub fn drain(&self) {
+        self.state.send_if_modified(|current| match current {
+            PurgerState::Running | PurgerState::Paused => {
+                info!("Purger draining");
+                PURGER_STATE.set(PurgerState::Draining.gauge_value());
+                *current = PurgerState::Draining;
+                true
+            }
+            _ => false,
+        });
+    }
+
+    pub fn subscribe(&self) -> watch::Receiver<PurgerState> {
+        self.state.subscribe()
+    }
+
+    /// Called by the purger between cycles, once nothing is in flight.
+    /// Completes a drain and waits for the purger to be resumed.
+    pub async fn wait_until_running(&self) {
+        let mut state = self.subscribe();
+        loop {
+            self.state.send_if_modified(|current| match current {
+                PurgerState::Draining => {
+                    info!("Purger drained");
+                    PURGER_STATE.set(PurgerState::Drained.gauge_value());
+                    *current = PurgerState::Drained;
+                    true
+                }
+                _ => false,
+            });
+            let next = state
+                .wait_for(|state| matches!(state, PurgerState::Running | PurgerState::Draining))
+                .await
+                .map(|state| *state);
+            if !matches!(next, Ok(PurgerState::Draining)) {
+                return;
+            }
+        }
+    }
+}
+
+/// `SIGTSTP` pauses the purger, `SIGUSR1` drains it and `SIGUSR2` resumes
+/// it. Ctrl-Z in a terminal thus pauses the purger rather than the process.
+pub async fn handle_signals(control: PurgerControl) -> anyhow::Result<()> {
+    let mut pause = signal(SignalKind::from_raw(libc::SIGTSTP))?;
+    let mut drain = signal(SignalKind::user_defined1())?;
+    let mut resume = signal(SignalKind::user_defined2())?;
+    loop {
+        tokio::select! {
+            _ = pause.recv() => control.pause(),
+            _ = drain.recv() => control.drain(),
+            _ = resume.recv() => control.resume(),
+        }
+    }
+}
@@ -0,0 +1,208 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hasher},
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use chrono::Utc;
+use dns_parser::{Builder, Packet, QueryClass, ResponseCode};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpStream, UdpSocket},
+    time::timeout,
+};
+
+use crate::{
+    chains::Chains,
+    event_manip::{BackendError, DnsRepopulate, RepopulateOutcome},
+    own_queries::InFlight,
+    settings::DnsQuerySettings,
+    structs::{DnsAnswer, RRsetKey, RecordType, Universe},
+    DNS_QUERY_DURATION_HISTOGRAM_VEC,
+};
+
+// Plain DNS over UDP without EDNS, larger answers come back truncated.
+const MAX_UDP_RESPONSE_SIZE: usize = 512;
+
+#[derive(Debug)]
+pub struct QueryResponse {
+    pub response_code: ResponseCode,
+    pub answers: Vec<DnsAnswer>,
+}
+
+impl QueryResponse {
+    /// Lowest TTL among the answers of the queried type, i.e. how long the
+    /// resolver will keep serving the RRset.
+    pub fn ttl(&self, record_type: &RecordType) -> Option<u32> {
+        self.answers
+            .iter()
+            .filter(|answer| &answer.record_type == record_type)
+            .map(|answer| answer.ttl)
+            .min()
+    }
+}
+
+fn query_id() -> u16 {
+    RandomState::new().build_hasher().finish() as u16
+}
+
+/// Minimal stub resolver: one question over UDP, retried over TCP when the
+/// answer comes back truncated.
+#[derive(Debug, Clone)]
+pub struct DnsQueryClient {
+    resolver: SocketAddr,
+    timeout: Duration,
+}
+
+impl DnsQueryClient {
+    pub fn new(resolver: SocketAddr, timeout: Duration) -> Self {
+        DnsQueryClient { resolver, timeout }
+    }
+
+    pub fn from_settings(settings: &DnsQuerySettings) -> anyhow::Result<Self> {
+        Ok(DnsQueryClient::new(
+            settings.resolver.parse()?,
+            Duration::from_millis(settings.timeout_ms),
+        ))
+    }
+
+    pub async fn query(
+        &self,
+        domain_name: &str,
+        record_type: &RecordType,
+        recursion_desired: bool,
+    ) -> Result<QueryResponse, BackendError> {
+        let id = query_id();
+        let mut builder = Builder::new_query(id, recursion_desired);
+        builder.add_question(domain_name, false, record_type.query_type(), QueryClass::IN);
+        let query = builder.build().unwrap_or_else(|truncated| truncated);
+
+        let started = Instant::now();
+        let mut response = timeout(self.timeout, self.exchange_udp(id, &query))
+            .await
+            .map_err(|_| BackendError::Protocol("UDP query timed out".to_string()))??;
+        let mut transport = "udp";
+        if Packet::parse(&response)
+            .map_err(|e| BackendError::Protocol(e.to_string()))?
+            .header
+            .truncated
+        {
+            response = timeout(self.timeout, self.exchange_tcp(&query))
+                .await
+                .map_err(|_| BackendError::Protocol("TCP query timed out".to_string()))??;
+            transport = "tcp";
+        }
+        DNS_QUERY_DURATION_HISTOGRAM_VEC
+            .with_label_values(&[transport])
+            .observe(started.elapsed().as_secs_f64());
+
+        let packet = Packet::parse(&response).map_err(|e| BackendError::Protocol(e.to_string()))?;
+        if packet.header.id != id {
+            return Err(BackendError::Protocol(format!(
+                "response id {} does not match query id {}",
+                packet.header.id, id
+            )));
+        }
+        let received = Utc::now();
+        Ok(QueryResponse {
+            response_code: packet.header.response_code,
+            answers: packet
+                .answers
+                .into_iter()
+                .filter_map(|answer| DnsAnswer::try_from((answer, received)).ok())
+                .map(|answer| DnsAnswer {
+                    source_address: Some(self.resolver.ip()),
+                    ..answer
+                })
+                .collect(),
+        })
+    }
+
+    async fn exchange_udp(&self, id: u16, query: &[u8]) -> Result<Vec<u8>, BackendError> {
+        let local: SocketAddr = match self.resolver {
+            SocketAddr::V4(_) => ([0, 0, 0, 0], 0).into(),
+            SocketAddr::V6(_) => ([0u16; 8], 0).into(),
+        };
+        let socket = UdpSocket::bind(local).await?;
+        socket.connect(self.resolver).await?;
+        let _own = InFlight::socket(socket.local_addr()?.port(), id);
+        socket.send(query).await?;
+        let mut buffer = vec![0; MAX_UDP_RESPONSE_SIZE];
+        let received = socket.recv(&mut buffer).await?;
+        buffer.truncate(received);
+        Ok(buffer)
+    }
+
+    async fn exchange_tcp(&self, query: &[u8]) -> Result<Vec<u8>, BackendError> {
+        let mut stream = TcpStream::connect(self.resolver).await?;
+        stream.write_u16(query.len() as u16).await?;
+        stream.write_all(query).await?;
+        let length = stream.read_u16().await?;
+        let mut buffer = vec![0; length as usize];
+        stream.read_exact(&mut buffer).await?;
+        Ok(buffer)
+    }
+}
+
+/// Refreshes records by asking the resolver for them directly and feeds the
+/// fresh answers back into the universe, so their new deadline is known
+/// without waiting for a client to ask again.
+pub struct DnsQueryRepopulator {
+    client: DnsQueryClient,
+    universe: Universe,
+    alias_chains: Chains,
+}
+
+impl DnsQueryRepopulator {
+    pub fn new(client: DnsQueryClient, universe: Universe, alias_chains: Chains) -> Self {
+        DnsQueryRepopulator {
+            client,
+            universe,
+            alias_chains,
+        }
+    }
+}
+
+#[async_trait]
+impl DnsRepopulate for DnsQueryRepopulator {
+    fn name(&self) -> &'static str {
+        "dns_query"
+    }
+
+    async fn repopulate(&self, rrset: &RRsetKey) -> Result<RepopulateOutcome, BackendError> {
+        let response = self
+            .client
+            .query(&rrset.domain_name, &rrset.record_type, true)
+            .await?;
+        if response.response_code == ResponseCode::ServerFailure {
+            return Err(BackendError::Upstream(
+                "resolver answered ServerFailure".to_string(),
+            ));
+        }
+        if response.response_code != ResponseCode::NoError {
+            return Err(BackendError::Protocol(format!(
+                "resolver answered {:?}",
+                response.response_code
+            )));
+        }
+        // NODATA, the name holding no records of the type, is an answer too,
+        // one without a TTL to report.
+        let outcome = RepopulateOutcome {
+            ttl: response.ttl(&rrset.record_type),
+        };
+
+        self.alias_chains
+            .write()
+            .await
+            .record_response(&response.answers);
+        let mut universe = self.universe.write().await;
+        for answer in response.answers {
+            if universe.should_track(&answer) {
+                universe.push(answer);
+            }
+        }
+        Ok(outcome)
+    }
+}
@@ -0,0 +1,81 @@
+use std::{collections::VecDeque, sync::Arc};
+
+use chrono::{DateTime, Utc};
+use log::{error, info};
+use serde::Serialize;
+use sqlx::{Pool, Postgres};
+use tokio::sync::RwLock;
+
+use crate::{
+    structs::{RRsetKey, RecordType},
+    PLANNED_ACTIONS_COUNTER_VEC,
+};
+
+pub type PlannedActions = Arc<RwLock<PlanLog>>;
+
+// Only the latest plans are kept in memory, the database keeps the rest.
+const PLAN_LOG_CAPACITY: usize = 1000;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PlannedAction {
+    pub planned_at: DateTime<Utc>,
+    pub action: &'static str,
+    pub target: String,
+    pub domain_name: String,
+    pub record_type: RecordType,
+}
+
+/// The most recent actions the purger would have taken.
+#[derive(Debug, Default)]
+pub struct PlanLog {
+    entries: VecDeque<PlannedAction>,
+}
+
+impl PlanLog {
+    pub fn record(&mut self, planned: PlannedAction) {
+        if self.entries.len() == PLAN_LOG_CAPACITY {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(planned);
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &PlannedAction> {
+        self.entries.iter()
+    }
+}
+
+/// Where the purger reports what it would do instead of doing it.
+#[derive(Debug, Clone)]
+pub struct DryRun {
+    pub planned: PlannedActions,
+    /// Set when planned actions are also stored in `planned_actions`.
+    pub pool: Option<Pool<Postgres>>,
+}
+
+impl DryRun {
+    pub async fn plan(&self, action: &'static str, target: &str, rrset: &RRsetKey) {
+        info!(
+            "Dry run: would {} {} {} on {}",
+            action,
+            rrset.domain_name,
+            rrset.record_type.form_for_command_line_arg(),
+            target
+        );
+        PLANNED_ACTIONS_COUNTER_VEC
+            .with_label_values(&[action, target])
+            .inc();
+        let planned = PlannedAction {
+            planned_at: Utc::now(),
+            action,
+            target: target.to_string(),
+            domain_name: rrset.domain_name.clone(),
+            record_type: rrset.record_type.clone(),
+        };
+        if let Some(pool) = &self.pool {
+            if let Err(e) = planned.insert(pool).await {
+                error!("Failed to store planned action: {:?}", e);
+            }
+        }
+        self.planned.write().await.record(planned);
+    }
+}
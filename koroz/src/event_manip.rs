@@ -1,156 +1,370 @@
-use std::collections::HashMap;
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, BinaryHeap, HashMap, HashSet};
+use std::fmt;
 use std::io::Error;
-use std::process::Output;
-use std::{collections::BinaryHeap, sync::Arc};
+use std::sync::Arc;
+use std::time::Instant;
 
-use chrono::{DateTime, Duration, Utc};
-use log::{error, info};
+use async_trait::async_trait;
+use futures::{future::join_all, FutureExt};
+
+use chrono::{DateTime, TimeDelta, Utc};
+use log::{debug, error, info, warn};
 use sqlx::{Pool, Postgres};
 use tokio::process::Command;
-use tokio::sync::{mpsc, RwLock};
-use tokio::task::{JoinError, JoinSet};
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::JoinSet;
+use tokio::time::MissedTickBehavior;
 
-use crate::structs::RecordType;
+use crate::audit::{ActionReport, AuditLog, Trigger};
+use crate::chains::{AliasChain, AliasChains, Chains};
+use crate::control::{PurgerControl, PurgerState};
+use crate::dry_run::DryRun;
+use crate::expiry_queue::{Admission, DueRecord, ExpiryQueue};
+use crate::fleet::Fleet;
+use crate::outage::{self, OutageTracker};
+use crate::own_queries::{self, InFlight};
+use crate::policy::{Policies, PolicyTable};
+use crate::popularity::{Popularity, PopularityTracker};
+use crate::public_suffix::registrable_domain;
+use crate::resilience::backoff_delay;
+use crate::settings::{ContainerSettings, RefreshStrategy};
+use crate::structs::{RRsetKey, RecordType, Universe};
+use crate::token_bucket::TokenBucket;
 use crate::{
     settings,
     structs::{DnsAnswer, DnsResponse},
 };
 use crate::{
-    ACTIONS_OVER_RECORDS_COUNTER, FAILED_COMMANDS_TO_EXECUTE_COUNTER_VEC,
-    FAILED_RECORDS_MANIPULATION_COUNTER_VEC, RECORDS_FOR_PURGING_SIZE,
+    ABUSIVE_PARENT_DOMAINS, ACTIONS_OVER_RECORDS_COUNTER, BACKEND_ACTION_DURATION_HISTOGRAM_VEC,
+    EVICTED_RECORDS_COUNTER_VEC, FAILED_COMMANDS_TO_EXECUTE_COUNTER_VEC,
+    FAILED_RECORDS_MANIPULATION_COUNTER_VEC, RECORDS_FOR_PURGING_SIZE, REFRESH_STALE_COUNTER_VEC,
+    REFRESH_VERIFIED_COUNTER_VEC, REQUEUED_RECORDS_COUNTER, RESOLVER_UP_VEC,
+    RETRIED_ACTIONS_COUNTER_VEC, THROTTLED_REFRESHES_COUNTER_VEC, TRACKED_RECORDS,
 };
 
-pub trait DnsInvalidate {
-    fn command_invalidate_name(&self, domain_name: &str, record_type: &RecordType) -> Command;
+// A name many aliases point at would otherwise fan out into as many
+// refreshes.
+const MAX_CHAINS_PER_RECORD: usize = 8;
+
+const FORGET_IDLE_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60);
+
+#[derive(Debug)]
+pub enum BackendError {
+    Io(Error),
+    Exit {
+        code: Option<i32>,
+        stderr: String,
+    },
+    Protocol(String),
+    /// The resolver could not get an answer from the authoritative servers.
+    Upstream(String),
 }
 
-pub trait DnsRepopulate {
-    fn command_repopulate_name(&self, domain_name: &str, record_type: &RecordType) -> Command;
+impl fmt::Display for BackendError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BackendError::Io(e) => write!(f, "I/O error: {}", e),
+            BackendError::Exit { code, stderr } => {
+                write!(f, "exited with {:?}: {}", code, stderr.trim())
+            }
+            BackendError::Protocol(message) => write!(f, "protocol error: {}", message),
+            BackendError::Upstream(message) => write!(f, "upstream failure: {}", message),
+        }
+    }
 }
 
-#[derive(Default, Debug)]
-pub struct UnboundInvalidator;
-#[derive(Default, Debug)]
-pub struct DockerUnboundInvalidator;
+impl std::error::Error for BackendError {}
 
+impl From<Error> for BackendError {
+    fn from(e: Error) -> Self {
+        BackendError::Io(e)
+    }
+}
+
+/// What an invalidation backend reported back, e.g. `ok` from unbound.
+#[derive(Debug, Clone, Default)]
+pub struct InvalidateOutcome {
+    pub output: String,
+}
+
+/// What a repopulation backend learned about the refreshed RRset. `ttl` is
+/// the fresh TTL when the backend can see the answer.
+#[derive(Debug, Clone, Default)]
+pub struct RepopulateOutcome {
+    pub ttl: Option<u32>,
+}
+
+pub(crate) async fn run_command(mut cmd: Command) -> Result<String, BackendError> {
+    let output = cmd.output().await?;
+    match output.status.success() {
+        true => Ok(String::from_utf8_lossy(&output.stdout).into_owned()),
+        false => Err(BackendError::Exit {
+            code: output.status.code(),
+            stderr: String::from_utf8_lossy(&output.stderr).into_owned(),
+        }),
+    }
+}
+
+#[async_trait]
+pub trait DnsInvalidate: Send + Sync {
+    /// Short backend name used in metrics and logs.
+    fn name(&self) -> &'static str;
+
+    async fn invalidate(&self, rrset: &RRsetKey) -> Result<InvalidateOutcome, BackendError>;
+
+    /// Flushes everything cached at and below `suffix`.
+    async fn flush_zone(&self, suffix: &str) -> Result<InvalidateOutcome, BackendError>;
+
+    /// Flushes a whole cycle's worth of RRsets, returning one result per
+    /// RRset in the same order. Backends with a batched command override
+    /// this; by default every RRset gets its own `invalidate`.
+    async fn invalidate_bulk(
+        &self,
+        rrsets: &[RRsetKey],
+    ) -> Vec<Result<InvalidateOutcome, BackendError>> {
+        join_all(rrsets.iter().map(|rrset| self.invalidate(rrset))).await
+    }
+}
+
+#[async_trait]
+pub trait DnsRepopulate: Send + Sync {
+    /// Short backend name used in metrics and logs.
+    fn name(&self) -> &'static str;
+
+    async fn repopulate(&self, rrset: &RRsetKey) -> Result<RepopulateOutcome, BackendError>;
+
+    /// Whether the backend can overwrite a cached RRset in place.
+    fn supports_replace(&self) -> bool {
+        false
+    }
+
+    /// Fetches the RRset upstream and swaps it for the cached copy in one
+    /// step, so clients never see a miss.
+    async fn replace(&self, _rrset: &RRsetKey) -> Result<RepopulateOutcome, BackendError> {
+        Err(BackendError::Protocol(format!(
+            "{} cannot replace cached records",
+            self.name()
+        )))
+    }
+}
+
+/// Builds the command for `program`, run through `<runtime> exec` when the
+/// resolver lives in a container.
+pub(crate) fn backend_command(container: Option<&ContainerSettings>, program: &str) -> Command {
+    let mut cmd = match container {
+        Some(container) => {
+            let mut cmd = Command::new(container.runtime.binary());
+            cmd.arg("exec")
+                .args(&container.exec_options)
+                .arg(&container.name)
+                .arg(program);
+            cmd
+        }
+        None => Command::new(program),
+    };
+    cmd.kill_on_drop(true);
+    cmd
+}
+
+/// Spawns `unbound-control flush_type`, inside the container when one is
+/// configured.
 #[derive(Default, Debug)]
-pub struct DigRepopulator;
-#[derive(Default, Debug)]
+pub struct UnboundInvalidator {
+    pub container: Option<ContainerSettings>,
+}
 
-pub struct DockerDigRepopulator;
+/// Spawns `dig`, inside the container when one is configured.
+#[derive(Default, Debug)]
+pub struct DigRepopulator {
+    pub container: Option<ContainerSettings>,
+}
 
+#[async_trait]
 impl DnsInvalidate for UnboundInvalidator {
-    fn command_invalidate_name(&self, domain_name: &str, record_type: &RecordType) -> Command {
-        let mut cmd = Command::new("unbound-control");
+    fn name(&self) -> &'static str {
+        "unbound_control"
+    }
+
+    async fn invalidate(&self, rrset: &RRsetKey) -> Result<InvalidateOutcome, BackendError> {
+        let mut cmd = backend_command(self.container.as_ref(), "unbound-control");
         cmd.arg("flush_type")
-            .kill_on_drop(true)
-            .arg(domain_name)
-            .arg(record_type.form_for_command_line_arg());
-        ACTIONS_OVER_RECORDS_COUNTER
-            .with_label_values(&["invalidate", record_type.form_for_command_line_arg()])
-            .inc();
-        cmd
-    }
-}
-
-impl DnsInvalidate for DockerUnboundInvalidator {
-    fn command_invalidate_name(&self, domain_name: &str, record_type: &RecordType) -> Command {
-        let mut cmd = Command::new("docker");
-        cmd.arg("exec")
-            .arg("-it")
-            .arg("my-unbound")
-            .arg("unbound-control")
-            .arg("flush")
-            .kill_on_drop(true)
-            .arg(domain_name)
-            .arg(record_type.form_for_command_line_arg());
-        ACTIONS_OVER_RECORDS_COUNTER
-            .with_label_values(&["invalidate", record_type.form_for_command_line_arg()])
-            .inc();
-        cmd
+            .arg(&rrset.domain_name)
+            .arg(rrset.record_type.form_for_command_line_arg());
+        let output = run_command(cmd).await?;
+        Ok(InvalidateOutcome { output })
+    }
+
+    async fn flush_zone(&self, suffix: &str) -> Result<InvalidateOutcome, BackendError> {
+        let mut cmd = backend_command(self.container.as_ref(), "unbound-control");
+        cmd.arg("flush_zone").arg(suffix);
+        let output = run_command(cmd).await?;
+        Ok(InvalidateOutcome { output })
     }
 }
 
+#[async_trait]
 impl DnsRepopulate for DigRepopulator {
-    fn command_repopulate_name(&self, domain_name: &str, record_type: &RecordType) -> Command {
-        let mut cmd = Command::new("dig");
-        cmd.arg(domain_name)
-            .arg("-t")
-            .arg(record_type.form_for_command_line_arg())
-            .kill_on_drop(true);
-        ACTIONS_OVER_RECORDS_COUNTER
-            .with_label_values(&["repopulate", record_type.form_for_command_line_arg()])
-            .inc();
-        cmd
-    }
-}
-impl DnsRepopulate for DockerDigRepopulator {
-    fn command_repopulate_name(&self, domain_name: &str, record_type: &RecordType) -> Command {
-        let mut cmd = Command::new("docker");
-        cmd.arg("exec")
-            .arg("-it")
-            .arg("my-unbound")
-            .arg("dig")
+    fn name(&self) -> &'static str {
+        "dig"
+    }
+
+    async fn repopulate(&self, rrset: &RRsetKey) -> Result<RepopulateOutcome, BackendError> {
+        let mut cmd = backend_command(self.container.as_ref(), "dig");
+        cmd.arg(&rrset.domain_name)
             .arg("-t")
-            .arg(record_type.form_for_command_line_arg())
-            .kill_on_drop(true)
-            .arg(domain_name);
-        ACTIONS_OVER_RECORDS_COUNTER
-            .with_label_values(&["repopulate", record_type.form_for_command_line_arg()])
-            .inc();
-        cmd
+            .arg(rrset.record_type.form_for_command_line_arg());
+        let _own = InFlight::name(&rrset.domain_name);
+        let output = run_command(cmd).await?;
+        if output.contains("status: SERVFAIL") {
+            return Err(BackendError::Upstream(
+                "resolver answered SERVFAIL".to_string(),
+            ));
+        }
+        Ok(RepopulateOutcome {
+            ttl: dig_answer_ttl(&output, &rrset.record_type),
+        })
     }
 }
 
-async fn process_command_end_output(
-    command_end: Result<Result<Output, Error>, JoinError>,
-    command_type: &str,
-) {
-    if let Ok(output_result) = command_end {
-        match output_result {
-            Ok(output) => match output.status.success() {
-                true => {}
-                false => {
-                    dbg!(&output);
-                    FAILED_COMMANDS_TO_EXECUTE_COUNTER_VEC
-                        .with_label_values(&[
-                            command_type,
-                            &output.status.code().unwrap_or(-1).to_string(),
-                        ])
-                        .inc()
+/// Lowest TTL among the answer lines of the queried type in dig's output,
+/// `www.example.com. 300 IN A 192.0.2.1`.
+fn dig_answer_ttl(output: &str, record_type: &RecordType) -> Option<u32> {
+    let queried = record_type.form_for_command_line_arg();
+    output
+        .lines()
+        .filter(|line| !line.starts_with(';'))
+        .filter_map(
+            |line| match line.split_whitespace().collect::<Vec<_>>()[..] {
+                [_, ttl, _, record_type, ..] if record_type.eq_ignore_ascii_case(queried) => {
+                    ttl.parse().ok()
                 }
+                _ => None,
             },
-            Err(_) => {
-                FAILED_RECORDS_MANIPULATION_COUNTER_VEC
-                    .with_label_values(&[command_type])
-                    .inc();
-            }
+        )
+        .min()
+}
+
+/// Metrics and the audit trail are recorded here, from what actually
+/// happened, rather than by the backends when they start working on a record.
+pub(crate) fn record_action_result<T>(
+    report: &ActionReport,
+    result: &Result<T, BackendError>,
+    audit: &AuditLog,
+) {
+    let ActionReport {
+        action,
+        backend,
+        target,
+        rrset,
+        elapsed,
+        ..
+    } = *report;
+    audit.record(report, result);
+    let result_label = match result {
+        Ok(_) => "success",
+        Err(_) => "failure",
+    };
+    BACKEND_ACTION_DURATION_HISTOGRAM_VEC
+        .with_label_values(&[action, backend, target, result_label])
+        .observe(elapsed.as_secs_f64());
+    RESOLVER_UP_VEC
+        .with_label_values(&[target])
+        .set(result.is_ok() as i64);
+    match result {
+        Ok(_) => ACTIONS_OVER_RECORDS_COUNTER
+            .with_label_values(&[action, rrset.record_type.form_for_command_line_arg()])
+            .inc(),
+        Err(BackendError::Exit { code, stderr }) => {
+            error!(
+                "{} of {} {} on {} via {} exited with {:?}: {}",
+                action,
+                rrset.domain_name,
+                rrset.record_type.form_for_command_line_arg(),
+                target,
+                backend,
+                code,
+                stderr.trim()
+            );
+            FAILED_COMMANDS_TO_EXECUTE_COUNTER_VEC
+                .with_label_values(&[action, &code.unwrap_or(-1).to_string()])
+                .inc()
+        }
+        Err(e) => {
+            error!(
+                "{} of {} {} on {} via {} failed: {}",
+                action,
+                rrset.domain_name,
+                rrset.record_type.form_for_command_line_arg(),
+                target,
+                backend,
+                e
+            );
+            FAILED_RECORDS_MANIPULATION_COUNTER_VEC
+                .with_label_values(&[action])
+                .inc();
         }
     }
 }
 
 pub async fn aggregate_dns_answers(
     mut rx: mpsc::Receiver<DnsResponse>,
-    dns_answer_set: Arc<RwLock<BinaryHeap<DnsAnswer>>>,
-    last_seen_answers: Arc<RwLock<HashMap<(String, RecordType), DateTime<Utc>>>>,
+    dns_answer_set: Universe,
+    alias_chains: Chains,
+    popularity: Popularity,
     pool: Pool<Postgres>,
 ) {
     info!("Started event collector");
-    while let Some(dns_answers) = rx.recv().await {
+    while let Some(response) = rx.recv().await {
+        // Answers to koroz's own refreshes keep the queue up to date but are
+        // no client lookups, counting them would keep one-off names warm.
+        let own = own_queries::is_own(&response);
         let mut dns_responses_container = dns_answer_set.write().await;
-        let mut last_seen_answers = last_seen_answers.write().await;
-        for dns_answer in dns_answers
-            .into_iter()
-            .filter(|answer| answer.has_reasonable_ttl())
-        {
-            dns_responses_container.push(dns_answer.clone());
-            last_seen_answers.insert(
-                (
-                    dns_answer.domain_name.clone(),
-                    dns_answer.record_type.clone(),
-                ),
-                dns_answer.expiration_time(),
-            );
+        // Only what the queue takes in counts towards popularity and alias
+        // chains, so floods it refuses cannot grow them either.
+        let mut tracked = vec![];
+        for dns_answer in response.answers {
+            if !dns_responses_container.should_track(&dns_answer) {
+                continue;
+            }
+            match dns_responses_container.push(dns_answer.clone()) {
+                Admission::Tracked => tracked.push(dns_answer),
+                Admission::Refused => {}
+                Admission::ZoneFlagged => {
+                    warn!(
+                        "Too many records under {}, no longer tracking it for a while",
+                        registrable_domain(&dns_answer.domain_name)
+                    );
+                    EVICTED_RECORDS_COUNTER_VEC
+                        .with_label_values(&["abusive_parent_domain"])
+                        .inc();
+                }
+            }
+        }
+        alias_chains.write().await.record_response(&tracked);
+        let mut popularity = popularity.write().await;
+        // A response holds a record per address of an RRset, it is still
+        // one lookup of it.
+        let mut hit = HashSet::new();
+        for dns_answer in tracked.iter() {
+            if !own && hit.insert(dns_answer.rrset_key()) {
+                popularity.hit(dns_answer.rrset_key(), dns_answer.read_from_buffer_ts);
+            }
+        }
+
+        let now = Utc::now();
+        let evicted =
+            dns_responses_container.evict_least_popular(|rrset| popularity.score(rrset, now));
+        EVICTED_RECORDS_COUNTER_VEC
+            .with_label_values(&["least_popular"])
+            .inc_by(evicted as u64);
+        TRACKED_RECORDS.set(dns_responses_container.len() as i64);
+        ABUSIVE_PARENT_DOMAINS.set(dns_responses_container.abusive_parent_domains() as i64);
+        // The purger and the API are not kept waiting on the database.
+        drop(popularity);
+        drop(dns_responses_container);
+
+        for dns_answer in tracked.iter() {
             if let Err(e) = dns_answer.upsert(&pool).await {
                 error!("Failed to insert DNS answer: {:?}", e);
             }
@@ -158,70 +372,827 @@ pub async fn aggregate_dns_answers(
     }
 }
 
-pub async fn purge_dns_records<I: DnsInvalidate, R: DnsRepopulate>(
-    dns_answer_set: Arc<RwLock<BinaryHeap<DnsAnswer>>>,
-    invalidator: I,
-    repopulator: R,
-    last_seen_answers: Arc<RwLock<HashMap<(String, RecordType), DateTime<Utc>>>>,
+/// What the tasks acting on resolvers share, the purger's and the API's.
+#[derive(Clone)]
+pub struct Dispatch {
+    pub(crate) fleet: Arc<Fleet>,
+    pub(crate) in_flight: Arc<Semaphore>,
+    pub(crate) dry_run: Option<DryRun>,
+    pub(crate) audit: AuditLog,
+}
+
+impl Dispatch {
+    pub fn new(fleet: Arc<Fleet>, dry_run: Option<DryRun>, audit: AuditLog) -> Self {
+        Dispatch {
+            fleet,
+            in_flight: Arc::new(Semaphore::new(settings().resilience.max_in_flight.max(1))),
+            dry_run,
+            audit,
+        }
+    }
+}
+
+/// Flushes `rrsets` on resolver `i`, at most `max_in_flight` records at a
+/// time, retrying the failed ones with backoff. Returns what still failed.
+async fn invalidate_on_target(
+    dispatch: Dispatch,
+    i: usize,
+    rrsets: Vec<(RRsetKey, Trigger)>,
+) -> Vec<RRsetKey> {
+    let resilience = settings().resilience;
+    let target = &dispatch.fleet.targets()[i];
+    let mut failed = vec![];
+    for chunk in rrsets.chunks(resilience.max_in_flight.max(1)) {
+        let mut pending = chunk.to_vec();
+        for attempt in 0..=resilience.max_retries {
+            if attempt > 0 {
+                tokio::time::sleep(backoff_delay(attempt - 1, &resilience)).await;
+                if !target.available() {
+                    break;
+                }
+                RETRIED_ACTIONS_COUNTER_VEC
+                    .with_label_values(&["invalidate", &target.name])
+                    .inc_by(pending.len() as u64);
+            }
+            let keys: Vec<RRsetKey> = pending.iter().map(|(rrset, _)| rrset.clone()).collect();
+            let permit = dispatch
+                .in_flight
+                .acquire_many(keys.len() as u32)
+                .await
+                .unwrap();
+            let started = Instant::now();
+            let results = target.invalidator.invalidate_bulk(&keys).await;
+            let elapsed = started.elapsed();
+            drop(permit);
+
+            let mut retry = vec![];
+            for ((rrset, trigger), result) in pending.into_iter().zip(results) {
+                target.record_outcome(result.is_ok());
+                if let Ok(outcome) = &result {
+                    debug!(
+                        "Invalidated {:?} on {}: {}",
+                        rrset,
+                        target.name,
+                        outcome.output.trim()
+                    );
+                }
+                let report = ActionReport {
+                    action: "invalidate",
+                    backend: target.invalidator.name(),
+                    target: &target.name,
+                    rrset: &rrset,
+                    trigger,
+                    elapsed,
+                };
+                record_action_result(&report, &result, &dispatch.audit);
+                if result.is_err() {
+                    retry.push((rrset, trigger));
+                }
+            }
+            pending = retry;
+            if pending.is_empty() {
+                break;
+            }
+        }
+        failed.extend(pending.into_iter().map(|(rrset, _)| rrset));
+    }
+    failed
+}
+
+/// Asks resolver `i` for `record` without recursion and checks that the
+/// cached TTL is close to `full_ttl`, i.e. the copy is fresh.
+async fn verify_on_target(
+    dispatch: Dispatch,
+    i: usize,
+    record: DnsAnswer,
+    full_ttl: u32,
+    min_ttl_ratio: f64,
+) -> bool {
+    let target = &dispatch.fleet.targets()[i];
+    let Some(verifier) = &target.verifier else {
+        return true;
+    };
+    let permit = dispatch.in_flight.acquire().await.unwrap();
+    let response = verifier
+        .query(&record.domain_name, &record.record_type, false)
+        .await;
+    drop(permit);
+
+    let cached_ttl = match response {
+        Ok(response) => response.ttl(&record.record_type),
+        Err(e) => {
+            warn!(
+                "Could not verify {} {} on {}: {}",
+                record.domain_name,
+                record.record_type.form_for_command_line_arg(),
+                target.name,
+                e
+            );
+            None
+        }
+    };
+    let fresh = cached_ttl.is_some_and(|ttl| ttl as f64 >= full_ttl as f64 * min_ttl_ratio);
+    match fresh {
+        true => REFRESH_VERIFIED_COUNTER_VEC
+            .with_label_values(&[&target.name])
+            .inc(),
+        false => {
+            debug!(
+                "{} {} on {} is stale after refresh, cached TTL {:?} of {}",
+                record.domain_name,
+                record.record_type.form_for_command_line_arg(),
+                target.name,
+                cached_ttl,
+                full_ttl
+            );
+            REFRESH_STALE_COUNTER_VEC
+                .with_label_values(&[&target.name])
+                .inc()
+        }
+    }
+    fresh
+}
+
+pub(crate) fn refresh_action(strategy: RefreshStrategy) -> &'static str {
+    match strategy {
+        RefreshStrategy::Replace => "replace",
+        _ => "repopulate",
+    }
+}
+
+/// Re-queries `rrset` on resolver `i`, or replaces it with the `replace`
+/// strategy, retrying with backoff. Returns the RRset back with the outcome
+/// of the last attempt.
+async fn repopulate_on_target(
+    dispatch: Dispatch,
+    i: usize,
+    rrset: RRsetKey,
+    trigger: Trigger,
+    strategy: RefreshStrategy,
+) -> (RRsetKey, Result<RepopulateOutcome, BackendError>) {
+    let resilience = settings().resilience;
+    let target = &dispatch.fleet.targets()[i];
+    let action = refresh_action(strategy);
+    let mut last_result = None;
+    for attempt in 0..=resilience.max_retries {
+        if attempt > 0 {
+            tokio::time::sleep(backoff_delay(attempt - 1, &resilience)).await;
+            if !target.available() {
+                break;
+            }
+            RETRIED_ACTIONS_COUNTER_VEC
+                .with_label_values(&[action, &target.name])
+                .inc();
+        }
+        let permit = dispatch.in_flight.acquire().await.unwrap();
+        let started = Instant::now();
+        let result = match strategy {
+            RefreshStrategy::Replace => target.repopulator.replace(&rrset).await,
+            _ => target.repopulator.repopulate(&rrset).await,
+        };
+        let elapsed = started.elapsed();
+        drop(permit);
+
+        target.record_outcome(result.is_ok());
+        if let Ok(RepopulateOutcome { ttl: Some(ttl) }) = &result {
+            debug!(
+                "Refreshed {:?} on {} with a fresh TTL of {}s",
+                rrset, target.name, ttl
+            );
+        }
+        let report = ActionReport {
+            action,
+            backend: target.repopulator.name(),
+            target: &target.name,
+            rrset: &rrset,
+            trigger,
+            elapsed,
+        };
+        record_action_result(&report, &result, &dispatch.audit);
+        if result.is_ok() {
+            return (rrset, result);
+        }
+        last_result = Some(result);
+    }
+    // The first attempt always runs.
+    (rrset, last_result.unwrap())
+}
+
+/// Chains a record is refreshed through, the most popular ones when more
+/// than `MAX_CHAINS_PER_RECORD` go through it.
+/// A due record waiting for room in the refresh budget, ordered by how
+/// popular it was when it came due.
+struct Ready {
+    score: f64,
+    due: DueRecord,
+}
+
+impl PartialEq for Ready {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for Ready {}
+
+impl PartialOrd for Ready {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Ready {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.score.total_cmp(&other.score)
+    }
+}
+
+/// `answer` with what was captured for it until it was popped this cycle,
+/// or on its own for records that were not, such as probes.
+fn due_record(popped: &HashMap<RRsetKey, DueRecord>, answer: &DnsAnswer) -> DueRecord {
+    match popped.get(&answer.rrset_key()) {
+        Some(due) => DueRecord {
+            answer: answer.clone(),
+            highest_ttl: due.highest_ttl,
+            served_by: due.served_by.clone(),
+            retries: due.retries,
+        },
+        None => DueRecord::from(answer.clone()),
+    }
+}
+
+fn followed_chains(
+    alias_chains: &AliasChains,
+    popularity: &PopularityTracker,
+    rrset: &RRsetKey,
+    now: DateTime<Utc>,
+) -> Vec<AliasChain> {
+    let mut chains = alias_chains.chains_with(rrset);
+    if chains.len() > MAX_CHAINS_PER_RECORD {
+        chains.sort_by(|a, b| {
+            popularity
+                .score(&b.query(), now)
+                .total_cmp(&popularity.score(&a.query(), now))
+        });
+        chains.truncate(MAX_CHAINS_PER_RECORD);
+    }
+    chains
+}
+
+/// What picking the records to refresh next looks at.
+struct Selection<'a> {
+    policies: &'a PolicyTable,
+    popularity: &'a PopularityTracker,
+    alias_chains: &'a AliasChains,
+    now: DateTime<Utc>,
+    started: Instant,
+}
+
+/// Records taken for one batch of refreshes.
+#[derive(Debug, Default)]
+struct Batch {
+    /// What was captured for each record until it was taken.
+    popped: HashMap<RRsetKey, DueRecord>,
+    records: Vec<DnsAnswer>,
+    /// Chains each record is refreshed through.
+    chains: Vec<Vec<AliasChain>>,
+    /// Queries the records take, one per chain or one for the record alone.
+    queries: usize,
+    /// How long until the first throttled record may go.
+    throttle_wait: Option<std::time::Duration>,
+}
+
+/// Moves the records that came due from the queue to `ready`, where they wait
+/// for room in the budget, most requested names first. Records are then
+/// taken until `budget` queries are used, whatever the upstream limits or the
+/// record's policy have no room for waits for the next batch.
+fn take_batch(
+    ready: &mut BinaryHeap<Ready>,
+    universe: &mut ExpiryQueue,
+    outages: &mut OutageTracker,
+    selection: &Selection,
+    budget: usize,
+) -> Batch {
+    let Selection {
+        policies,
+        popularity,
+        alias_chains,
+        now,
+        started,
+    } = *selection;
+    while universe.next_due().is_some_and(|due| due <= now) {
+        let due = universe.pop().unwrap();
+        let score = popularity.score(&due.answer.rrset_key(), now);
+        ready.push(Ready { score, due });
+    }
+
+    let mut batch = Batch::default();
+    let mut deferred = vec![];
+    while let Some(Ready { score, due }) = ready.pop() {
+        let rrset = due.answer.rrset_key();
+        // A newer answer was captured while this one waited.
+        if universe.contains(&rrset) {
+            universe.requeue(due, None);
+            continue;
+        }
+        // One-off lookups are left to expire instead of being kept warm,
+        // and so is whatever a policy says never to refresh.
+        if due.answer.expiration_time() <= now
+            || !policies.should_refresh(&rrset, popularity.score(&rrset, now))
+        {
+            continue;
+        }
+        // Zones in outage are left to the resolver's serve-expired.
+        let Some(answer) = outages.hold(due.answer.clone()) else {
+            continue;
+        };
+        let chains = followed_chains(alias_chains, popularity, &rrset, now);
+        let queries = chains.len().max(1);
+        if batch.queries > 0 && batch.queries + queries > budget {
+            ready.push(Ready { score, due });
+            break;
+        }
+        match policies.try_acquire(&rrset, queries, started) {
+            Err((throttle, wait)) => {
+                THROTTLED_REFRESHES_COUNTER_VEC
+                    .with_label_values(&[throttle.as_str()])
+                    .inc();
+                batch.throttle_wait = Some(
+                    batch
+                        .throttle_wait
+                        .map_or(wait, |shortest| shortest.min(wait)),
+                );
+                deferred.push(Ready { score, due });
+            }
+            Ok(()) => {
+                batch.queries += queries;
+                batch.popped.insert(rrset, due);
+                batch.records.push(answer);
+                batch.chains.push(chains);
+            }
+        }
+    }
+    ready.extend(deferred);
+    batch
+}
+
+pub async fn purge_dns_records(
+    dns_answer_set: Universe,
+    dispatch: Dispatch,
+    policies: Policies,
+    alias_chains: Chains,
+    popularity: Popularity,
+    control: PurgerControl,
 ) {
     info!("Started purger/repopulator");
-    let min_time_to_expire_to_purge = settings().min_time_to_expire_to_purge;
+    let fleet = Arc::clone(&dispatch.fleet);
+    let mut state_changed = control.subscribe();
+    let refresh_strategy = settings().refresh_strategy;
+    let verification = settings().verification;
+    let deadline_changed = dns_answer_set.read().await.deadline_changed();
+    let longest_sleep = std::time::Duration::from_secs(settings().purge_wake_up_interval);
+    // Refreshes go out at max_records_to_refresh_in_cycle per
+    // purge_wake_up_interval, a second's worth at a time at most.
+    let refresh_rate = settings().max_records_to_refresh_in_cycle as f64
+        / settings().purge_wake_up_interval.max(1) as f64;
+    let mut pacer = TokenBucket::new(refresh_rate, refresh_rate);
+    let mut outages = OutageTracker::from_settings(&settings().outage);
+    let mut ready: BinaryHeap<Ready> = BinaryHeap::new();
+    let mut forget_idle = tokio::time::interval(FORGET_IDLE_INTERVAL);
+    forget_idle.set_missed_tick_behavior(MissedTickBehavior::Delay);
     loop {
+        // Scores and limits nobody asked for lately are let go now and then,
+        // it takes a scan of them all.
+        if forget_idle.tick().now_or_never().is_some() {
+            popularity.write().await.forget_idle(Utc::now());
+            policies.forget_idle(Instant::now());
+            let now = Utc::now();
+            ready.retain(|ready| ready.due.answer.expiration_time() > now);
+        }
+        control.wait_until_running().await;
+        // With every resolver behind an open circuit breaker there is nowhere
+        // to refresh records on, they wait in the queue.
+        if let Some(until) = fleet.unavailable_until() {
+            let wait = until.saturating_duration_since(Instant::now());
+            if !wait.is_zero() {
+                tokio::select! {
+                    _ = tokio::time::sleep(wait.min(longest_sleep)) => {}
+                    _ = state_changed.changed() => {}
+                }
+                continue;
+            }
+        }
+        // Sleep until the first record or probe is due, until something due
+        // earlier arrives, or until the purger is paused or drained.
+        let next_due = dns_answer_set.read().await.next_due();
+        let mut until_due = match next_due {
+            _ if !ready.is_empty() => std::time::Duration::ZERO,
+            Some(due) => (due - Utc::now()).to_std().unwrap_or_default(),
+            None => longest_sleep,
+        };
+        if let Some(next_probe) = outages.next_probe() {
+            until_due = until_due.min(next_probe.saturating_duration_since(Instant::now()));
+        }
+        if !until_due.is_zero() {
+            tokio::select! {
+                _ = tokio::time::sleep(until_due.min(longest_sleep)) => {}
+                _ = deadline_changed.notified() => {}
+                _ = state_changed.changed() => {}
+            }
+            continue;
+        }
+        let wait = pacer.wait_time(Instant::now());
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+        let budget = pacer.available(Instant::now());
+
         let mut read_dns_answers = dns_answer_set.write().await;
-        let last_seen_answers = last_seen_answers.read().await;
-
-        let mut records_for_purging = vec![];
-        while let Some(answer) = read_dns_answers.peek() {
-            match answer.expiration_time().signed_duration_since(Utc::now())
-                > Duration::seconds(min_time_to_expire_to_purge)
-            {
-                true => break,
-                false => {}
-            }
-            let answer = read_dns_answers.pop().unwrap();
-            match last_seen_answers.get(&(answer.domain_name.clone(), answer.record_type.clone())) {
-                Some(dt) => {
-                    if dt == &answer.expiration_time() {
-                        records_for_purging.push(answer);
-                    }
+        let now = Utc::now();
+        let started = Instant::now();
+        let popularity = popularity.read().await;
+        let alias_chains = alias_chains.read().await;
+        let selection = Selection {
+            policies: &policies,
+            popularity: &popularity,
+            alias_chains: &alias_chains,
+            now,
+            started,
+        };
+        let Batch {
+            popped,
+            records: mut records_for_purging,
+            chains: mut record_chains,
+            queries,
+            throttle_wait,
+        } = take_batch(
+            &mut ready,
+            &mut read_dns_answers,
+            &mut outages,
+            &selection,
+            budget,
+        );
+        pacer.take(queries);
+        // Probes are never flushed, a zone in outage would lose the good
+        // answers still cached for it. They bypass the cache where the backend
+        // can, so that an answer shows the zone answering again.
+        let first_probe = records_for_purging.len();
+        for probe in outages.probes(started) {
+            record_chains.push(followed_chains(
+                &alias_chains,
+                &popularity,
+                &probe.rrset_key(),
+                now,
+            ));
+            records_for_purging.push(probe);
+        }
+        drop(alias_chains);
+        drop(popularity);
+        // Repopulators may feed fresh answers back into the universe.
+        drop(read_dns_answers);
+        if records_for_purging.is_empty() {
+            if let Some(wait) = throttle_wait {
+                tokio::time::sleep(wait).await;
+            }
+            continue;
+        }
+
+        // Resolvers behind an open circuit breaker are left alone, records
+        // with nowhere left to go wait in the queue.
+        let available: Vec<bool> = fleet
+            .targets()
+            .iter()
+            .map(|target| target.available())
+            .collect();
+        let mut requeue = BTreeSet::new();
+        // Records the resolver still held a stale copy of after a refresh.
+        let mut stale = BTreeSet::new();
+
+        // Records reached through an alias are refreshed as a whole chain and
+        // re-queried by the owner name, which is what clients actually ask for.
+        // Each record goes to the resolvers that served it, or to the one its
+        // policy names.
+        let mut records_to_invalidate: HashMap<RRsetKey, BTreeSet<usize>> = HashMap::new();
+        let mut records_to_repopulate: HashMap<(RRsetKey, RefreshStrategy), BTreeSet<usize>> =
+            HashMap::new();
+        // Purged records each action is done on behalf of, put back in the
+        // queue when the action fails.
+        let mut origins: HashMap<RRsetKey, BTreeSet<usize>> = HashMap::new();
+        // Why each action is taken, after the first record asking for it.
+        let mut triggers: HashMap<RRsetKey, Trigger> = HashMap::new();
+        // Resolvers each purged record is refreshed on.
+        let mut record_targets: Vec<(usize, Vec<usize>)> = vec![];
+        for (n, record) in records_for_purging.iter().enumerate() {
+            let policy = policies.matching(&record.rrset_key());
+            let targets: Vec<usize> = match policy.and_then(|policy| policy.target) {
+                Some(target) => vec![target],
+                None => fleet.targets_for(&due_record(&popped, record).served_by),
+            }
+            .into_iter()
+            .filter(|&i| available[i])
+            .collect();
+            if targets.is_empty() {
+                requeue.insert(n);
+                continue;
+            }
+            record_targets.push((n, targets.clone()));
+            let strategy = match n >= first_probe {
+                true => RefreshStrategy::QueryOnly,
+                false => policy
+                    .and_then(|policy| policy.strategy)
+                    .unwrap_or(refresh_strategy),
+            };
+            let trigger = match policy.is_some_and(|policy| policy.overrides_schedule()) {
+                true => Trigger::Policy,
+                false => Trigger::Ttl,
+            };
+            let mut rrsets = vec![record.rrset_key()];
+            let mut queries = vec![];
+            let chains = &record_chains[n];
+            if chains.is_empty() {
+                queries.push(record.rrset_key());
+            }
+            for chain in chains {
+                rrsets.extend(chain.records());
+                queries.push(chain.query());
+            }
+            // The other strategies refresh in place, nothing gets flushed.
+            if strategy != RefreshStrategy::FlushThenQuery {
+                rrsets.clear();
+            }
+            for rrset in rrsets {
+                origins.entry(rrset.clone()).or_default().insert(n);
+                triggers.entry(rrset.clone()).or_insert(trigger);
+                records_to_invalidate
+                    .entry(rrset)
+                    .or_default()
+                    .extend(&targets);
+            }
+            for query in queries {
+                origins.entry(query.clone()).or_default().insert(n);
+                triggers.entry(query.clone()).or_insert(trigger);
+                for &i in &targets {
+                    let strategy = match n >= first_probe
+                        && fleet.targets()[i].repopulator.supports_replace()
+                    {
+                        true => RefreshStrategy::Replace,
+                        false => strategy,
+                    };
+                    records_to_repopulate
+                        .entry((query.clone(), strategy))
+                        .or_default()
+                        .insert(i);
                 }
-                None => {
-                    records_for_purging.push(answer);
+            }
+        }
+
+        if let Some(dry_run) = &dispatch.dry_run {
+            for (rrset, targets) in &records_to_invalidate {
+                for &i in targets {
+                    dry_run
+                        .plan("invalidate", &fleet.targets()[i].name, rrset)
+                        .await;
                 }
             }
-            if records_for_purging.len() == settings::settings().max_records_to_refresh_in_cycle {
-                break;
+            for ((rrset, strategy), targets) in &records_to_repopulate {
+                for &i in targets {
+                    dry_run
+                        .plan(refresh_action(*strategy), &fleet.targets()[i].name, rrset)
+                        .await;
+                }
             }
+            continue;
         }
-        let mut invalidation_commands: JoinSet<_> = records_for_purging
-            .iter()
-            .map(|record| {
-                invalidator
-                    .command_invalidate_name(&record.domain_name, &record.record_type)
-                    .output()
-            })
+
+        // Pausing stops flushing right away, what was taken for this cycle
+        // waits in the queue until the purger is resumed.
+        if control.state() == PurgerState::Paused {
+            let mut universe = dns_answer_set.write().await;
+            for record in &records_for_purging {
+                universe.requeue(due_record(&popped, record), None);
+            }
+            continue;
+        }
+
+        // One batch per resolver, so a cycle costs a round trip per resolver
+        // on backends that can flush many records at once.
+        let mut batches: HashMap<usize, Vec<(RRsetKey, Trigger)>> = HashMap::new();
+        for (rrset, targets) in records_to_invalidate {
+            for i in targets {
+                batches
+                    .entry(i)
+                    .or_default()
+                    .push((rrset.clone(), triggers[&rrset]));
+            }
+        }
+        let mut invalidation_commands: JoinSet<_> = batches
+            .into_iter()
+            .map(|(i, rrsets)| invalidate_on_target(dispatch.clone(), i, rrsets))
             .collect();
         while let Some(command_end) = invalidation_commands.join_next().await {
-            process_command_end_output(command_end, "invalidate").await;
+            match command_end {
+                Ok(failed) => {
+                    for rrset in failed {
+                        requeue.extend(origins.get(&rrset).into_iter().flatten());
+                    }
+                }
+                Err(e) => error!("invalidation task failed: {}", e),
+            }
         }
 
-        let mut repopulation_commands: JoinSet<_> = records_for_purging
-            .iter()
-            .map(|record| {
-                repopulator
-                    .command_repopulate_name(&record.domain_name, &record.record_type)
-                    .output()
+        // Records held back by an outage that just ended.
+        let mut released = vec![];
+        let mut repopulation_commands: JoinSet<_> = records_to_repopulate
+            .into_iter()
+            .flat_map(|((rrset, strategy), targets)| {
+                targets
+                    .into_iter()
+                    .map(move |i| (rrset.clone(), strategy, i))
+            })
+            .map(|(rrset, strategy, i)| {
+                let trigger = triggers[&rrset];
+                let repopulation =
+                    repopulate_on_target(dispatch.clone(), i, rrset, trigger, strategy);
+                async move { (i, strategy, repopulation.await) }
             })
             .collect();
-
+        // Full TTL each purged record was refreshed with, per resolver.
+        let mut fresh_ttls: HashMap<(usize, usize), u32> = HashMap::new();
         while let Some(command_end) = repopulation_commands.join_next().await {
-            process_command_end_output(command_end, "repopulate").await;
+            let (i, strategy, (rrset, result)) = match command_end {
+                Ok(result) => result,
+                Err(e) => {
+                    error!("repopulation task failed: {}", e);
+                    continue;
+                }
+            };
+            // Only the authoritative servers failing puts a zone in outage,
+            // not the resolver or the backend misbehaving.
+            let refreshed = match &result {
+                Ok(_) => Some(true),
+                Err(BackendError::Upstream(_)) => Some(false),
+                Err(_) => None,
+            };
+            for &n in origins.get(&rrset).into_iter().flatten() {
+                if result.is_err() {
+                    requeue.insert(n);
+                }
+                if let Ok(RepopulateOutcome { ttl: Some(ttl) }) = &result {
+                    if records_for_purging[n].rrset_key() == rrset {
+                        fresh_ttls.insert((n, i), *ttl);
+                    }
+                }
+                // A probe that only queried may have been answered by
+                // serve-expired while the zone is still down.
+                let refreshed = match (refreshed, &result) {
+                    (Some(true), Ok(outcome))
+                        if n >= first_probe && strategy != RefreshStrategy::Replace =>
+                    {
+                        outages.answered_upstream(outcome).then_some(true)
+                    }
+                    (refreshed, _) => refreshed,
+                };
+                if let Some(refreshed) = refreshed {
+                    let zone = outage::zone(&records_for_purging[n].domain_name);
+                    released.extend(outages.record(&zone, refreshed, Instant::now()));
+                }
+            }
         }
-        RECORDS_FOR_PURGING_SIZE.set(read_dns_answers.len() as f64);
 
-        tokio::time::sleep(tokio::time::Duration::from_secs(
-            settings::settings().purge_wake_up_interval,
-        ))
-        .await;
+        // A refresh the resolver does not show is as good as a failed one. The
+        // cached TTL is compared with the one the refresh reported, or with
+        // the highest one captured when the backend does not report any.
+        if verification.enabled {
+            let mut verifications: JoinSet<_> = record_targets
+                .into_iter()
+                .filter(|(n, _)| !requeue.contains(n))
+                .flat_map(|(n, targets)| targets.into_iter().map(move |i| (n, i)))
+                .map(|(n, i)| {
+                    let dispatch = dispatch.clone();
+                    let record = records_for_purging[n].clone();
+                    let full_ttl = fresh_ttls
+                        .get(&(n, i))
+                        .copied()
+                        .unwrap_or_else(|| due_record(&popped, &record).highest_ttl);
+                    async move {
+                        let fresh = verify_on_target(
+                            dispatch,
+                            i,
+                            record,
+                            full_ttl,
+                            verification.min_ttl_ratio,
+                        )
+                        .await;
+                        (n, fresh)
+                    }
+                })
+                .collect();
+            while let Some(verification_end) = verifications.join_next().await {
+                match verification_end {
+                    Ok((n, false)) => {
+                        requeue.insert(n);
+                        stale.insert(n);
+                    }
+                    Ok((_, true)) => {}
+                    Err(e) => error!("verification task failed: {}", e),
+                }
+            }
+        }
+
+        // Failed records get another chance, after a backoff growing with
+        // each failure, until they expire, unless a fresh answer for them
+        // arrived in the meantime or their zone is now in outage.
+        let now = Utc::now();
+        let resilience = settings().resilience;
+        let mut universe = dns_answer_set.write().await;
+        for n in requeue {
+            let record = &records_for_purging[n];
+            if record.expiration_time() > now && !universe.contains(&record.rrset_key()) {
+                if let Some(record) = outages.hold(record.clone()) {
+                    let mut due = due_record(&popped, &record);
+                    // Refreshing what the resolver will not take over and
+                    // over would only keep flushing it.
+                    if stale.contains(&n) && due.retries >= verification.max_retries {
+                        continue;
+                    }
+                    let backoff = backoff_delay(due.retries, &resilience);
+                    due.retries += 1;
+                    let not_before = now + TimeDelta::from_std(backoff).unwrap_or_default();
+                    if universe.requeue(due, Some(not_before)) {
+                        REQUEUED_RECORDS_COUNTER.inc();
+                    }
+                }
+            }
+        }
+        for record in released {
+            if record.expiration_time() > now {
+                universe.requeue(record.into(), None);
+            }
+        }
+        drop(universe);
+        RECORDS_FOR_PURGING_SIZE.set((dns_answer_set.read().await.len() + ready.len()) as f64);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+    use crate::settings::{OutageSettings, Settings};
+    use crate::structs::Cls;
+
+    fn answer(domain_name: &str, ttl: u32) -> DnsAnswer {
+        DnsAnswer {
+            domain_name: domain_name.to_string(),
+            ttl,
+            cls: Cls::IN,
+            record_type: RecordType::A,
+            read_from_buffer_ts: Utc.timestamp_opt(1_700_000_000, 0).unwrap(),
+            alias_target: None,
+            source_address: None,
+            destination_address: None,
+        }
+    }
+
+    #[test]
+    fn takes_the_most_popular_due_records_first() {
+        let policies = Arc::new(PolicyTable::from_settings(&Settings::default()).unwrap());
+        let mut universe = ExpiryQueue::new(100, 100, TimeDelta::hours(1), Arc::clone(&policies));
+        let mut popularity = PopularityTracker::new(3600, 100);
+        // The least popular records are due first.
+        for (domain_name, ttl, hits) in [
+            ("a.example", 600, 0),
+            ("b.example", 601, 1),
+            ("c.example", 602, 3),
+            ("d.example", 603, 2),
+        ] {
+            let answer = answer(domain_name, ttl);
+            for _ in 0..hits {
+                popularity.hit(answer.rrset_key(), answer.read_from_buffer_ts);
+            }
+            universe.push(answer);
+        }
+        let alias_chains = AliasChains::new(100);
+        let mut outages = OutageTracker::from_settings(&OutageSettings::default());
+        let selection = Selection {
+            policies: &policies,
+            popularity: &popularity,
+            alias_chains: &alias_chains,
+            now: Utc.timestamp_opt(1_700_000_400, 0).unwrap(),
+            started: Instant::now(),
+        };
+        let mut ready = BinaryHeap::new();
+        let mut take = |budget| -> Vec<String> {
+            take_batch(&mut ready, &mut universe, &mut outages, &selection, budget)
+                .records
+                .into_iter()
+                .map(|record| record.domain_name)
+                .collect()
+        };
+
+        assert_eq!(take(2), ["c.example", "d.example"]);
+        assert_eq!(take(10), ["b.example", "a.example"]);
+        assert!(take(10).is_empty());
     }
 }
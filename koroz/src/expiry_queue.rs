@@ -0,0 +1,558 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    net::IpAddr,
+    sync::Arc,
+};
+
+use tokio::sync::Notify;
+
+use chrono::{DateTime, TimeDelta, Utc};
+
+use crate::policy::Policies;
+use crate::public_suffix::{self, is_public_suffix, registrable_domain};
+use crate::structs::{DnsAnswer, RRsetKey, ScheduleKey};
+
+// Eviction frees a little more than strictly needed so that a queue sitting
+// at its cap does not pay for a full scan on every insert.
+pub(crate) const EVICTION_HEADROOM: f64 = 0.9;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Admission {
+    Tracked,
+    /// The answer belongs to a registrable domain that was flagged as
+    /// abusive.
+    Refused,
+    /// This answer pushed its registrable domain over the limit, the domain
+    /// was flagged and everything tracked under it dropped.
+    ZoneFlagged,
+}
+
+/// A record taken off the queue, with what was captured for its RRset since
+/// it was last taken.
+#[derive(Debug, Clone)]
+pub struct DueRecord {
+    pub answer: DnsAnswer,
+    /// `answer.ttl` is only what was left of the latest answer.
+    pub highest_ttl: u32,
+    /// Addresses of every resolver seen serving the RRset, the latest answer
+    /// only shows one.
+    pub served_by: BTreeSet<IpAddr>,
+    /// Failed refreshes in a row.
+    pub retries: u32,
+}
+
+impl From<DnsAnswer> for DueRecord {
+    fn from(answer: DnsAnswer) -> Self {
+        DueRecord {
+            highest_ttl: answer.ttl,
+            served_by: answer.endpoints().collect(),
+            retries: 0,
+            answer,
+        }
+    }
+}
+
+/// The latest answer per RRset, ordered by when it needs attention: soonest
+/// refresh first, ties broken by name and then by record type. An answer is
+/// due its policy's lead time before it expires.
+///
+/// A newer answer for an RRset that is already queued replaces it and moves
+/// its deadline, so the queue grows with the number of distinct RRsets and
+/// not with traffic.
+#[derive(Debug)]
+pub struct ExpiryQueue {
+    schedule: BTreeSet<ScheduleKey>,
+    deadline_changed: Arc<Notify>,
+    policies: Policies,
+    answers: HashMap<RRsetKey, DnsAnswer>,
+    /// Highest TTL captured per RRset since it was last popped, which a
+    /// refresh is verified against.
+    highest_ttls: HashMap<RRsetKey, u32>,
+    served_by: HashMap<RRsetKey, BTreeSet<IpAddr>>,
+    retries: HashMap<RRsetKey, u32>,
+    /// Put back after failing to refresh, not due again before then.
+    not_before: HashMap<RRsetKey, DateTime<Utc>>,
+    max_records: usize,
+    max_records_per_parent_domain: usize,
+    records_per_parent_domain: HashMap<String, usize>,
+    /// When each flag is lifted.
+    abusive_parent_domains: HashMap<String, DateTime<Utc>>,
+    flag_duration: TimeDelta,
+}
+
+impl ExpiryQueue {
+    pub fn new(
+        max_records: usize,
+        max_records_per_parent_domain: usize,
+        flag_duration: TimeDelta,
+        policies: Policies,
+    ) -> Self {
+        ExpiryQueue {
+            schedule: BTreeSet::new(),
+            deadline_changed: Arc::new(Notify::new()),
+            policies,
+            answers: HashMap::new(),
+            highest_ttls: HashMap::new(),
+            served_by: HashMap::new(),
+            retries: HashMap::new(),
+            not_before: HashMap::new(),
+            max_records,
+            max_records_per_parent_domain,
+            records_per_parent_domain: HashMap::new(),
+            abusive_parent_domains: HashMap::new(),
+            flag_duration,
+        }
+    }
+
+    /// Signalled whenever an answer lands in front of the queue, i.e. the
+    /// next deadline moved earlier.
+    pub fn deadline_changed(&self) -> Arc<Notify> {
+        Arc::clone(&self.deadline_changed)
+    }
+
+    fn schedule_key(&self, answer: &DnsAnswer) -> ScheduleKey {
+        let mut schedule_key = answer.schedule_key(self.policies.lead_time(answer));
+        if let Some(not_before) = self.not_before.get(&schedule_key.rrset) {
+            schedule_key.due = schedule_key.due.max(*not_before);
+        }
+        schedule_key
+    }
+
+    /// Whether the answer is worth tracking at all, see
+    /// `PolicyTable::should_track`.
+    pub fn should_track(&self, answer: &DnsAnswer) -> bool {
+        self.policies.should_track(answer)
+    }
+
+    fn is_flagged(&self, parent: &str, now: DateTime<Utc>) -> bool {
+        self.abusive_parent_domains
+            .get(parent)
+            .is_some_and(|until| *until > now)
+    }
+
+    pub fn push(&mut self, answer: DnsAnswer) -> Admission {
+        let parent = registrable_domain(&answer.domain_name);
+        if self.is_flagged(&parent, Utc::now()) {
+            return Admission::Refused;
+        }
+
+        let schedule_key = self.schedule_key(&answer);
+        let is_new = !self.answers.contains_key(&schedule_key.rrset);
+        if is_new {
+            // Names directly under a public suffix are as many registrable
+            // domains, they are never flagged together. Without a public
+            // suffix list, that cannot be told, so nothing is flagged.
+            let limited = public_suffix::is_loaded() && !is_public_suffix(&parent);
+            let tracked_under_parent = self
+                .records_per_parent_domain
+                .entry(parent.clone())
+                .or_default();
+            if limited && *tracked_under_parent >= self.max_records_per_parent_domain {
+                self.flag_abusive(parent);
+                return Admission::ZoneFlagged;
+            }
+            *tracked_under_parent += 1;
+        }
+
+        let highest_ttl = self
+            .highest_ttls
+            .entry(schedule_key.rrset.clone())
+            .or_default();
+        *highest_ttl = (*highest_ttl).max(answer.ttl);
+        self.served_by
+            .entry(schedule_key.rrset.clone())
+            .or_default()
+            .extend(answer.endpoints());
+        if let Some(previous) = self.answers.insert(schedule_key.rrset.clone(), answer) {
+            self.schedule.remove(&self.schedule_key(&previous));
+        }
+        let earliest = self
+            .schedule
+            .first()
+            .is_none_or(|first| schedule_key < *first);
+        self.schedule.insert(schedule_key);
+        if earliest {
+            self.deadline_changed.notify_one();
+        }
+        Admission::Tracked
+    }
+
+    /// Until `flag_duration` from now, when the domain gets another chance.
+    fn flag_abusive(&mut self, parent: String) {
+        let now = Utc::now();
+        self.abusive_parent_domains.retain(|_, until| *until > now);
+        let flagged: Vec<RRsetKey> = self
+            .answers
+            .keys()
+            .filter(|rrset| registrable_domain(&rrset.domain_name) == parent)
+            .cloned()
+            .collect();
+        for rrset in flagged {
+            self.remove(&rrset);
+        }
+        self.records_per_parent_domain.remove(&parent);
+        self.abusive_parent_domains
+            .insert(parent, now + self.flag_duration);
+    }
+
+    fn remove(&mut self, rrset: &RRsetKey) -> Option<DnsAnswer> {
+        let answer = self.answers.remove(rrset)?;
+        self.schedule.remove(&self.schedule_key(&answer));
+        self.highest_ttls.remove(rrset);
+        self.served_by.remove(rrset);
+        self.retries.remove(rrset);
+        self.not_before.remove(rrset);
+        let parent = registrable_domain(&answer.domain_name);
+        if let Some(tracked_under_parent) = self.records_per_parent_domain.get_mut(&parent) {
+            *tracked_under_parent -= 1;
+            if *tracked_under_parent == 0 {
+                self.records_per_parent_domain.remove(&parent);
+            }
+        }
+        Some(answer)
+    }
+
+    /// Drops the least popular records once the queue is over its cap and
+    /// returns how many were dropped. Ties go to the record expiring first.
+    pub fn evict_least_popular<F: Fn(&RRsetKey) -> f64>(&mut self, popularity: F) -> usize {
+        if self.answers.len() <= self.max_records {
+            return 0;
+        }
+        let keep = (self.max_records as f64 * EVICTION_HEADROOM) as usize;
+        let mut candidates: Vec<(f64, ScheduleKey)> = self
+            .schedule
+            .iter()
+            .map(|schedule_key| (popularity(&schedule_key.rrset), schedule_key.clone()))
+            .collect();
+        candidates.sort_by(|a, b| a.0.total_cmp(&b.0).then_with(|| a.1.cmp(&b.1)));
+        let evicted = candidates.len() - keep;
+        for (_, schedule_key) in candidates.into_iter().take(evicted) {
+            self.remove(&schedule_key.rrset);
+        }
+        evicted
+    }
+
+    pub fn next_due(&self) -> Option<DateTime<Utc>> {
+        self.schedule.first().map(|schedule_key| schedule_key.due)
+    }
+
+    /// The record due first.
+    pub fn pop(&mut self) -> Option<DueRecord> {
+        let schedule_key = self.schedule.first()?.clone();
+        let highest_ttl = self.highest_ttls.get(&schedule_key.rrset).copied();
+        let served_by = self.served_by.get(&schedule_key.rrset).cloned();
+        let retries = self.retries.get(&schedule_key.rrset).copied();
+        let answer = self.remove(&schedule_key.rrset)?;
+        Some(DueRecord {
+            highest_ttl: highest_ttl.unwrap_or(answer.ttl),
+            served_by: served_by.unwrap_or_default(),
+            retries: retries.unwrap_or_default(),
+            answer,
+        })
+    }
+
+    /// Puts a record back after it could not be refreshed, unless a newer
+    /// answer for it was captured meanwhile, keeping what was captured for it
+    /// either way. It is not due again before `not_before`, so that a record
+    /// failing to refresh is not popped again right away. Returns whether it
+    /// was put back.
+    pub fn requeue(&mut self, due: DueRecord, not_before: Option<DateTime<Utc>>) -> bool {
+        let rrset = due.answer.rrset_key();
+        let requeued =
+            !self.answers.contains_key(&rrset) && self.push(due.answer) == Admission::Tracked;
+        if !self.answers.contains_key(&rrset) {
+            return false;
+        }
+        let highest_ttl = self.highest_ttls.entry(rrset.clone()).or_default();
+        *highest_ttl = (*highest_ttl).max(due.highest_ttl);
+        self.served_by
+            .entry(rrset.clone())
+            .or_default()
+            .extend(due.served_by);
+        if requeued {
+            if due.retries > 0 {
+                self.retries.insert(rrset.clone(), due.retries);
+            }
+            if let Some(not_before) = not_before {
+                let previous = self.schedule_key(&self.answers[&rrset]);
+                self.schedule.remove(&previous);
+                self.not_before.insert(rrset.clone(), not_before);
+                self.schedule
+                    .insert(self.schedule_key(&self.answers[&rrset]));
+            }
+        }
+        requeued
+    }
+
+    pub fn contains(&self, rrset: &RRsetKey) -> bool {
+        self.answers.contains_key(rrset)
+    }
+
+    pub fn len(&self) -> usize {
+        self.answers.len()
+    }
+
+    pub fn abusive_parent_domains(&self) -> usize {
+        let now = Utc::now();
+        self.abusive_parent_domains
+            .values()
+            .filter(|until| **until > now)
+            .count()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &DnsAnswer> {
+        self.schedule
+            .iter()
+            .filter_map(|schedule_key| self.answers.get(&schedule_key.rrset))
+    }
+}
+
+impl Extend<DnsAnswer> for ExpiryQueue {
+    fn extend<T: IntoIterator<Item = DnsAnswer>>(&mut self, answers: T) {
+        for answer in answers {
+            self.push(answer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cmp::Ordering;
+
+    use chrono::TimeZone;
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::policy::PolicyTable;
+    use crate::settings::Settings;
+    use crate::structs::{Cls, RecordType};
+
+    fn key(due: i64, domain_name: &str, record_type: RecordType) -> ScheduleKey {
+        ScheduleKey {
+            due: Utc.timestamp_opt(due, 0).unwrap(),
+            rrset: RRsetKey {
+                domain_name: domain_name.to_string(),
+                record_type,
+            },
+        }
+    }
+
+    fn answer(domain_name: &str, record_type: RecordType, ttl: u32) -> DnsAnswer {
+        DnsAnswer {
+            domain_name: domain_name.to_string(),
+            ttl,
+            cls: Cls::IN,
+            record_type,
+            read_from_buffer_ts: Utc.timestamp_opt(1_700_000_000, 0).unwrap(),
+            alias_target: None,
+            source_address: None,
+            destination_address: None,
+        }
+    }
+
+    fn queue() -> ExpiryQueue {
+        let policies = PolicyTable::from_settings(&Settings::default()).unwrap();
+        ExpiryQueue::new(100, 100, TimeDelta::hours(1), Arc::new(policies))
+    }
+
+    #[test]
+    fn schedule_keys_order_by_due_then_name_then_type() {
+        let ordered = [
+            key(10, "b.example", RecordType::AAAA),
+            key(20, "a.example", RecordType::AAAA),
+            key(20, "b.example", RecordType::A),
+            key(20, "b.example", RecordType::AAAA),
+            key(30, "a.example", RecordType::A),
+        ];
+        for pair in ordered.windows(2) {
+            assert!(pair[0] < pair[1], "{:?} < {:?}", pair[0], pair[1]);
+        }
+        let mut shuffled = ordered.to_vec();
+        shuffled.reverse();
+        shuffled.sort();
+        assert_eq!(shuffled, ordered);
+    }
+
+    #[test]
+    fn schedule_key_ordering_agrees_with_equality() {
+        let keys = [
+            key(10, "a.example", RecordType::A),
+            key(10, "a.example", RecordType::A),
+            key(10, "a.example", RecordType::MX),
+            key(10, "b.example", RecordType::A),
+            key(11, "a.example", RecordType::A),
+        ];
+        for a in &keys {
+            for b in &keys {
+                assert_eq!(a.cmp(b) == Ordering::Equal, a == b, "{:?} {:?}", a, b);
+                assert_eq!(a.cmp(b), b.cmp(a).reverse(), "{:?} {:?}", a, b);
+                assert_eq!(a.partial_cmp(b), Some(a.cmp(b)));
+            }
+        }
+    }
+
+    #[test]
+    fn pops_soonest_deadline_first() {
+        let mut queue = queue();
+        queue.push(answer("late.example", RecordType::A, 900));
+        queue.push(answer("early.example", RecordType::A, 400));
+        queue.push(answer("early.example", RecordType::AAAA, 400));
+        queue.push(answer("middle.example", RecordType::A, 600));
+
+        let popped: Vec<RRsetKey> = std::iter::from_fn(|| queue.pop())
+            .map(|due| due.answer.rrset_key())
+            .collect();
+        let expected = [
+            ("early.example", RecordType::A),
+            ("early.example", RecordType::AAAA),
+            ("middle.example", RecordType::A),
+            ("late.example", RecordType::A),
+        ]
+        .map(|(domain_name, record_type)| RRsetKey {
+            domain_name: domain_name.to_string(),
+            record_type,
+        });
+        assert_eq!(popped, expected);
+        assert_eq!(queue.len(), 0);
+    }
+
+    #[test]
+    fn newer_answer_moves_the_deadline() {
+        let mut queue = queue();
+        queue.push(answer("a.example", RecordType::A, 400));
+        queue.push(answer("b.example", RecordType::A, 600));
+        queue.push(answer("a.example", RecordType::A, 900));
+
+        assert_eq!(queue.len(), 2);
+        assert_eq!(queue.pop().unwrap().answer.domain_name, "b.example");
+        let a = queue.pop().unwrap().answer;
+        assert_eq!((a.domain_name.as_str(), a.ttl), ("a.example", 900));
+        assert!(queue.pop().is_none());
+    }
+
+    #[test]
+    fn pop_reports_the_highest_ttl_since_the_last_pop() {
+        let mut queue = queue();
+        queue.push(answer("a.example", RecordType::A, 3600));
+        queue.push(answer("a.example", RecordType::A, 1200));
+        let a = queue.pop().unwrap();
+        assert_eq!((a.answer.ttl, a.highest_ttl), (1200, 3600));
+
+        queue.push(answer("a.example", RecordType::A, 600));
+        assert_eq!(queue.pop().unwrap().highest_ttl, 600);
+    }
+
+    #[test]
+    fn keeps_every_resolver_an_rrset_was_served_by() {
+        let served_by = |resolver: [u8; 4]| DnsAnswer {
+            source_address: Some(resolver.into()),
+            ..answer("a.example", RecordType::A, 600)
+        };
+        let mut queue = queue();
+        queue.push(served_by([192, 0, 2, 1]));
+        queue.push(served_by([192, 0, 2, 2]));
+        let due = queue.pop().unwrap();
+        let expected = BTreeSet::from([[192, 0, 2, 1].into(), [192, 0, 2, 2].into()]);
+        assert_eq!(due.served_by, expected);
+
+        // Neither resolver is forgotten when the record is put back and
+        // served by one of them again.
+        assert!(queue.requeue(due, None));
+        queue.push(served_by([192, 0, 2, 2]));
+        assert_eq!(queue.pop().unwrap().served_by, expected);
+    }
+
+    #[test]
+    fn requeued_record_waits_until_it_may_be_retried() {
+        let mut queue = queue();
+        queue.push(answer("a.example", RecordType::A, 60));
+        queue.push(answer("b.example", RecordType::A, 600));
+        let mut due = queue.pop().unwrap();
+        assert_eq!(due.answer.domain_name, "a.example");
+
+        due.retries += 1;
+        let retry_at = Utc.timestamp_opt(1_700_003_600, 0).unwrap();
+        assert!(queue.requeue(due, Some(retry_at)));
+        assert_eq!(queue.pop().unwrap().answer.domain_name, "b.example");
+        assert_eq!(queue.next_due(), Some(retry_at));
+        assert_eq!(queue.pop().unwrap().retries, 1);
+    }
+
+    #[test]
+    fn flags_nothing_without_a_public_suffix_list() {
+        let policies = PolicyTable::from_settings(&Settings::default()).unwrap();
+        let mut queue = ExpiryQueue::new(100, 2, TimeDelta::hours(1), Arc::new(policies));
+        for site in ["www.bbc.co.uk", "www.gov.co.uk", "shop.example.co.uk"] {
+            assert_eq!(
+                queue.push(answer(site, RecordType::A, 600)),
+                Admission::Tracked
+            );
+        }
+        assert_eq!(queue.abusive_parent_domains(), 0);
+    }
+
+    const NAMES: [&str; 3] = ["a.example", "b.example", "c.example"];
+    const TYPES: [RecordType; 3] = [RecordType::A, RecordType::AAAA, RecordType::MX];
+
+    fn arbitrary_key() -> impl Strategy<Value = ScheduleKey> {
+        (0..4i64, 0..NAMES.len(), 0..TYPES.len())
+            .prop_map(|(due, name, record_type)| key(due, NAMES[name], TYPES[record_type].clone()))
+    }
+
+    proptest! {
+        #[test]
+        fn schedule_key_ordering_is_a_total_order(
+            a in arbitrary_key(),
+            b in arbitrary_key(),
+            c in arbitrary_key(),
+        ) {
+            prop_assert_eq!(a.cmp(&b) == Ordering::Equal, a == b);
+            prop_assert_eq!(a.cmp(&b), b.cmp(&a).reverse());
+            prop_assert_eq!(a.partial_cmp(&b), Some(a.cmp(&b)));
+            if a <= b && b <= c {
+                prop_assert!(a <= c);
+            }
+        }
+
+        /// Pushes, some replacing a queued RRset, and pops in any order come
+        /// out as from a map of the latest answers sorted by deadline.
+        #[test]
+        fn pops_in_the_order_of_a_sorted_model(
+            operations in prop::collection::vec(
+                prop::option::weighted(
+                    0.75,
+                    (0..NAMES.len(), 0..TYPES.len(), 0..3600u32, 0..60i64),
+                ),
+                0..64,
+            ),
+        ) {
+            let mut queue = queue();
+            let mut model: HashMap<RRsetKey, DnsAnswer> = HashMap::new();
+            let pop_model = |model: &mut HashMap<RRsetKey, DnsAnswer>| {
+                let first = model
+                    .values()
+                    .min_by_key(|answer| (answer.expiration_time(), answer.rrset_key()))
+                    .cloned()?;
+                model.remove(&first.rrset_key())
+            };
+            for operation in operations {
+                match operation {
+                    Some((name, record_type, ttl, seen)) => {
+                        let mut pushed = answer(NAMES[name], TYPES[record_type].clone(), ttl);
+                        pushed.read_from_buffer_ts += TimeDelta::seconds(seen);
+                        model.insert(pushed.rrset_key(), pushed.clone());
+                        queue.push(pushed);
+                    }
+                    None => {
+                        prop_assert_eq!(queue.pop().map(|due| due.answer), pop_model(&mut model));
+                    }
+                }
+                prop_assert_eq!(queue.len(), model.len());
+            }
+            while let Some(expected) = pop_model(&mut model) {
+                prop_assert_eq!(queue.pop().map(|due| due.answer), Some(expected));
+            }
+            prop_assert!(queue.pop().is_none());
+        }
+    }
+}
@@ -0,0 +1,246 @@
+use std::{
+    collections::BTreeSet,
+    net::{IpAddr, Ipv4Addr},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context as _};
+
+use crate::{
+    chains::Chains,
+    dns_query::{DnsQueryClient, DnsQueryRepopulator},
+    event_manip::{DigRepopulator, DnsInvalidate, DnsRepopulate, UnboundInvalidator},
+    knot::{KnotControlClient, KnotManagerClient},
+    powerdns::PowerDnsInvalidator,
+    resilience::CircuitBreaker,
+    rndc::RndcInvalidator,
+    settings::{
+        self, FleetDispatch, InvalidatorKind, RefreshStrategy, RepopulatorKind, ResolverSettings,
+    },
+    structs::Universe,
+    unbound_control::UnboundControlClient,
+    CIRCUIT_BREAKER_STATE_VEC,
+};
+
+const ETHERNET_HEADER_LENGTH: usize = 14;
+const ETHERTYPE_IPV4: [u8; 2] = [0x08, 0x00];
+const IPPROTO_UDP: u8 = 17;
+
+/// Source and destination of the IPv4 packet in an Ethernet `frame`.
+pub fn packet_addresses(frame: &[u8]) -> Option<(IpAddr, IpAddr)> {
+    if frame.get(12..ETHERNET_HEADER_LENGTH)? != ETHERTYPE_IPV4 {
+        return None;
+    }
+    let ip_header = frame.get(ETHERNET_HEADER_LENGTH..ETHERNET_HEADER_LENGTH + 20)?;
+    let source: [u8; 4] = ip_header[12..16].try_into().ok()?;
+    let destination: [u8; 4] = ip_header[16..20].try_into().ok()?;
+    Some((
+        Ipv4Addr::from(source).into(),
+        Ipv4Addr::from(destination).into(),
+    ))
+}
+
+/// Destination port of the UDP datagram in an Ethernet `frame`, the port a
+/// response goes back to.
+pub fn udp_destination_port(frame: &[u8]) -> Option<u16> {
+    if frame.get(12..ETHERNET_HEADER_LENGTH)? != ETHERTYPE_IPV4
+        || *frame.get(ETHERNET_HEADER_LENGTH + 9)? != IPPROTO_UDP
+    {
+        return None;
+    }
+    let port = frame.get(ETHERNET_HEADER_LENGTH + 22..ETHERNET_HEADER_LENGTH + 24)?;
+    Some(u16::from_be_bytes([port[0], port[1]]))
+}
+
+pub struct ResolverTarget {
+    pub name: String,
+    pub addresses: Vec<IpAddr>,
+    pub invalidator: Arc<dyn DnsInvalidate>,
+    pub repopulator: Arc<dyn DnsRepopulate>,
+    /// Asks the resolver what it has cached, when verification is enabled.
+    pub verifier: Option<DnsQueryClient>,
+    breaker: Mutex<CircuitBreaker>,
+}
+
+impl ResolverTarget {
+    pub fn from_settings(
+        settings: &ResolverSettings,
+        universe: &Universe,
+        alias_chains: &Chains,
+    ) -> anyhow::Result<Self> {
+        let container = settings.container.clone();
+        let invalidator: Arc<dyn DnsInvalidate> = match settings.invalidator {
+            InvalidatorKind::UnboundControl => Arc::new(UnboundInvalidator { container }),
+            InvalidatorKind::UnboundTls => Arc::new(
+                UnboundControlClient::from_settings(&settings.unbound_control).with_context(
+                    || {
+                        format!(
+                            "{}: failed to set up the unbound remote control client",
+                            settings.name
+                        )
+                    },
+                )?,
+            ),
+            InvalidatorKind::KnotControl => {
+                Arc::new(KnotControlClient::from_settings(&settings.knot))
+            }
+            InvalidatorKind::KnotManager => {
+                Arc::new(KnotManagerClient::from_settings(&settings.knot))
+            }
+            InvalidatorKind::PowerDns => {
+                Arc::new(PowerDnsInvalidator::from_settings(&settings.powerdns))
+            }
+            InvalidatorKind::Rndc => Arc::new(RndcInvalidator::new(&settings.rndc, container)),
+        };
+
+        let container = settings.container.clone();
+        let repopulator: Arc<dyn DnsRepopulate> = match settings.repopulator {
+            RepopulatorKind::Dig => Arc::new(DigRepopulator { container }),
+            RepopulatorKind::DnsQuery => Arc::new(DnsQueryRepopulator::new(
+                DnsQueryClient::from_settings(&settings.dns_query).with_context(|| {
+                    format!("{}: invalid dns_query.resolver address", settings.name)
+                })?,
+                Arc::clone(universe),
+                Arc::clone(alias_chains),
+            )),
+            RepopulatorKind::KnotControl => {
+                Arc::new(KnotControlClient::from_settings(&settings.knot))
+            }
+            RepopulatorKind::PowerDns => Arc::new(DnsQueryRepopulator::new(
+                DnsQueryClient::new(
+                    settings.powerdns.dns_address.parse().with_context(|| {
+                        format!("{}: invalid powerdns.dns_address", settings.name)
+                    })?,
+                    Duration::from_millis(settings.dns_query.timeout_ms),
+                ),
+                Arc::clone(universe),
+                Arc::clone(alias_chains),
+            )),
+        };
+
+        let replaces = settings::settings().refresh_strategy == RefreshStrategy::Replace
+            || settings::settings().policies.iter().any(|policy| {
+                policy.strategy == Some(RefreshStrategy::Replace)
+                    && policy
+                        .target
+                        .as_ref()
+                        .is_none_or(|target| *target == settings.name)
+            });
+        if replaces && !repopulator.supports_replace() {
+            bail!(
+                "{}: the {} repopulator cannot replace cached records",
+                settings.name,
+                repopulator.name()
+            );
+        }
+
+        let verifier = match settings::settings().verification.enabled {
+            true => {
+                // Only a repopulator that queries the resolver itself says
+                // where it answers, otherwise the address must be given.
+                let address = match (&settings.verification_address, settings.repopulator) {
+                    (Some(address), _) => address,
+                    (None, RepopulatorKind::PowerDns) => &settings.powerdns.dns_address,
+                    (None, RepopulatorKind::DnsQuery) => &settings.dns_query.resolver,
+                    (None, _) => bail!(
+                        "{}: verification needs a verification_address with the {} repopulator",
+                        settings.name,
+                        repopulator.name()
+                    ),
+                };
+                Some(DnsQueryClient::new(
+                    address.parse().with_context(|| {
+                        format!("{}: invalid address to verify refreshes on", settings.name)
+                    })?,
+                    Duration::from_millis(settings.dns_query.timeout_ms),
+                ))
+            }
+            false => None,
+        };
+
+        Ok(ResolverTarget {
+            name: settings.name.clone(),
+            addresses: settings.addresses.clone(),
+            invalidator,
+            repopulator,
+            verifier,
+            breaker: Mutex::new(CircuitBreaker::from_settings(
+                &settings::settings().resilience,
+            )),
+        })
+    }
+
+    /// False while the resolver's circuit breaker is open.
+    pub fn available(&self) -> bool {
+        let mut breaker = self.breaker.lock().unwrap();
+        let available = breaker.allows(Instant::now());
+        self.export_breaker_state(&breaker);
+        available
+    }
+
+    /// When the resolver gets a trial action, while its breaker is open.
+    pub fn unavailable_until(&self) -> Option<Instant> {
+        self.breaker.lock().unwrap().reopens_at()
+    }
+
+    pub fn record_outcome(&self, success: bool) {
+        let mut breaker = self.breaker.lock().unwrap();
+        breaker.record(success, Instant::now());
+        self.export_breaker_state(&breaker);
+    }
+
+    fn export_breaker_state(&self, breaker: &CircuitBreaker) {
+        CIRCUIT_BREAKER_STATE_VEC
+            .with_label_values(&[&self.name])
+            .set(breaker.state().gauge_value());
+    }
+
+    fn served(&self, served_by: &BTreeSet<IpAddr>) -> bool {
+        served_by
+            .iter()
+            .any(|address| self.addresses.contains(address))
+    }
+}
+
+/// Every resolver this instance keeps warm.
+pub struct Fleet {
+    targets: Vec<ResolverTarget>,
+    dispatch: FleetDispatch,
+}
+
+impl Fleet {
+    pub fn new(targets: Vec<ResolverTarget>, dispatch: FleetDispatch) -> Self {
+        Fleet { targets, dispatch }
+    }
+
+    pub fn targets(&self) -> &[ResolverTarget] {
+        &self.targets
+    }
+
+    /// When the first resolver is available again, while none of them is.
+    pub fn unavailable_until(&self) -> Option<Instant> {
+        self.targets
+            .iter()
+            .map(ResolverTarget::unavailable_until)
+            .collect::<Option<Vec<Instant>>>()?
+            .into_iter()
+            .min()
+    }
+
+    /// Indices of the resolvers a record served by these addresses should be
+    /// refreshed on, every one of them that served it. Records no resolver
+    /// claims, e.g. seen on an address nobody configured, go to all.
+    pub fn targets_for(&self, served_by: &BTreeSet<IpAddr>) -> Vec<usize> {
+        let matching: Vec<usize> = match self.dispatch {
+            FleetDispatch::Matching => (0..self.targets.len())
+                .filter(|&i| self.targets[i].served(served_by))
+                .collect(),
+            FleetDispatch::All => vec![],
+        };
+        match matching.is_empty() {
+            true => (0..self.targets.len()).collect(),
+            false => matching,
+        }
+    }
+}
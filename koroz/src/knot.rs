@@ -0,0 +1,342 @@
+use std::{
+    hash::{BuildHasher, Hasher, RandomState},
+    path::PathBuf,
+    time::{Duration, Instant},
+};
+
+use async_trait::async_trait;
+use serde::Serialize;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::UnixStream,
+};
+
+use crate::{
+    event_manip::{
+        BackendError, DnsInvalidate, DnsRepopulate, InvalidateOutcome, RepopulateOutcome,
+    },
+    settings::KnotSettings,
+    structs::{RRsetKey, RecordType},
+};
+
+// Switches the control socket from the interactive prompt to replies
+// prefixed with their length, the same mode `kresc` uses.
+const BINARY_MODE: &[u8] = b"__binary\n";
+
+const RESOLVE_TIMEOUT: Duration = Duration::from_secs(5);
+const RESOLVE_POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+const NOERROR: u16 = 0;
+const SERVFAIL: u16 = 2;
+
+/// Quotes `value` for the control socket, which runs whatever it is sent,
+/// so every byte but printable ASCII is written as a `\ddd` escape.
+fn lua_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('\'');
+    for byte in value.bytes() {
+        match byte {
+            b'\\' | b'\'' => quoted.push_str(&format!("\\{}", byte as char)),
+            b' '..=b'~' => quoted.push(byte as char),
+            _ => quoted.push_str(&format!("\\{:03}", byte)),
+        }
+    }
+    quoted.push('\'');
+    quoted
+}
+
+fn lua_record_type(record_type: &RecordType) -> Option<String> {
+    match record_type {
+        RecordType::Other => None,
+        _ => Some(format!(
+            "kres.type.{}",
+            record_type.form_for_command_line_arg().to_uppercase()
+        )),
+    }
+}
+
+/// Knot Resolver 5 (`kresd`) through its Lua control socket.
+pub struct KnotControlClient {
+    socket: PathBuf,
+}
+
+impl KnotControlClient {
+    pub fn from_settings(settings: &KnotSettings) -> Self {
+        KnotControlClient {
+            socket: PathBuf::from(&settings.control_socket),
+        }
+    }
+
+    /// Evaluates one Lua expression and returns what kresd printed for it.
+    pub async fn execute(&self, lua: &str) -> Result<String, BackendError> {
+        let mut stream = UnixStream::connect(&self.socket).await?;
+        stream.write_all(BINARY_MODE).await?;
+        stream.write_all(format!("{}\n", lua).as_bytes()).await?;
+        stream.flush().await?;
+
+        let length = stream.read_u32().await?;
+        let mut reply = vec![0; length as usize];
+        stream.read_exact(&mut reply).await?;
+        let reply = String::from_utf8_lossy(&reply).into_owned();
+        match reply.starts_with("error") {
+            true => Err(BackendError::Protocol(reply.trim().to_string())),
+            false => Ok(reply),
+        }
+    }
+
+    /// `resolve()` returns as soon as the query is scheduled, so its finish
+    /// callback leaves the RCODE and the TTL in `koroz_results`, polled here
+    /// until they are there. Abandoning a query drops its slot, which the
+    /// callback then leaves alone.
+    async fn resolve(
+        &self,
+        rrset: &RRsetKey,
+        options: &str,
+    ) -> Result<RepopulateOutcome, BackendError> {
+        let record_type =
+            lua_record_type(&rrset.record_type).unwrap_or_else(|| "kres.type.ANY".to_string());
+        let id = lua_string(&format!(
+            "koroz-{:x}",
+            RandomState::new().build_hasher().finish()
+        ));
+        self.execute(&format!(
+            "(function() \
+                koroz_results = koroz_results or {{}} \
+                koroz_results[{id}] = true \
+                resolve({name}, {record_type}, kres.class.IN, {options}, function(answer) \
+                    if not koroz_results[{id}] then return end \
+                    local ttl = -1 \
+                    for _, rr in ipairs(answer:section(kres.section.ANSWER)) do \
+                        if rr.type == {record_type} and (ttl < 0 or rr.ttl < ttl) then ttl = rr.ttl end \
+                    end \
+                    koroz_results[{id}] = {{answer:rcode(), ttl}} \
+                end) \
+            end)()",
+            id = id,
+            name = lua_string(&rrset.domain_name),
+            record_type = record_type,
+            options = options,
+        ))
+        .await?;
+
+        let poll = format!(
+            "(function() \
+                local result = koroz_results[{id}] \
+                if result == true then return 'pending' end \
+                koroz_results[{id}] = nil \
+                if result == nil then return 'lost' end \
+                return result[1] .. ' ' .. result[2] \
+            end)()",
+            id = id
+        );
+        let started = Instant::now();
+        let reply = loop {
+            let reply = self.execute(&poll).await?;
+            let reply = reply.trim().trim_matches(['\'', '"']).to_string();
+            if reply != "pending" {
+                break reply;
+            }
+            if started.elapsed() >= RESOLVE_TIMEOUT {
+                self.execute(&format!("koroz_results[{}] = nil", id))
+                    .await?;
+                return Err(BackendError::Protocol(format!(
+                    "no answer within {:?}",
+                    RESOLVE_TIMEOUT
+                )));
+            }
+            tokio::time::sleep(RESOLVE_POLL_INTERVAL).await;
+        };
+
+        let parsed = reply
+            .split_once(' ')
+            .and_then(|(rcode, ttl)| Some((rcode.parse::<u16>().ok()?, ttl.parse::<i64>().ok()?)));
+        match parsed {
+            Some((NOERROR, ttl)) => Ok(RepopulateOutcome {
+                ttl: u32::try_from(ttl).ok(),
+            }),
+            Some((SERVFAIL, _)) => Err(BackendError::Upstream(
+                "resolver answered SERVFAIL".to_string(),
+            )),
+            Some((rcode, _)) => Err(BackendError::Protocol(format!(
+                "resolver answered RCODE {}",
+                rcode
+            ))),
+            None => Err(BackendError::Protocol(format!(
+                "unexpected resolve result {}",
+                reply
+            ))),
+        }
+    }
+}
+
+#[async_trait]
+impl DnsInvalidate for KnotControlClient {
+    fn name(&self) -> &'static str {
+        "knot_control"
+    }
+
+    async fn invalidate(&self, rrset: &RRsetKey) -> Result<InvalidateOutcome, BackendError> {
+        let lua = match lua_record_type(&rrset.record_type) {
+            Some(record_type) => format!(
+                "cache.clear({}, true, {})",
+                lua_string(&rrset.domain_name),
+                record_type
+            ),
+            None => format!("cache.clear({}, true)", lua_string(&rrset.domain_name)),
+        };
+        let output = self.execute(&lua).await?;
+        Ok(InvalidateOutcome { output })
+    }
+
+    async fn flush_zone(&self, suffix: &str) -> Result<InvalidateOutcome, BackendError> {
+        let output = self
+            .execute(&format!("cache.clear({})", lua_string(suffix)))
+            .await?;
+        Ok(InvalidateOutcome { output })
+    }
+
+    /// Clears every RRset with a single Lua chunk, so the whole batch costs
+    /// one round trip. kresd evaluates it as a whole, so all records share
+    /// its outcome.
+    async fn invalidate_bulk(
+        &self,
+        rrsets: &[RRsetKey],
+    ) -> Vec<Result<InvalidateOutcome, BackendError>> {
+        if rrsets.is_empty() {
+            return vec![];
+        }
+        let entries: Vec<String> = rrsets
+            .iter()
+            .map(|rrset| {
+                format!(
+                    "{{{}, {}}}",
+                    lua_string(&rrset.domain_name),
+                    lua_record_type(&rrset.record_type).unwrap_or_else(|| "nil".to_string())
+                )
+            })
+            .collect();
+        let lua = format!(
+            "(function() for _, rrset in ipairs({{{}}}) do cache.clear(rrset[1], true, rrset[2]) end return {} end)()",
+            entries.join(", "),
+            rrsets.len()
+        );
+        match self.execute(&lua).await {
+            Ok(output) => rrsets
+                .iter()
+                .map(|_| {
+                    Ok(InvalidateOutcome {
+                        output: output.clone(),
+                    })
+                })
+                .collect(),
+            Err(e) => {
+                let message = e.to_string();
+                rrsets
+                    .iter()
+                    .map(|_| Err(BackendError::Protocol(message.clone())))
+                    .collect()
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl DnsRepopulate for KnotControlClient {
+    fn name(&self) -> &'static str {
+        "knot_control"
+    }
+
+    /// Asks kresd to resolve the name itself, which lands the answer in its
+    /// cache without a round trip through a client socket.
+    async fn repopulate(&self, rrset: &RRsetKey) -> Result<RepopulateOutcome, BackendError> {
+        self.resolve(rrset, "{}").await
+    }
+
+    fn supports_replace(&self) -> bool {
+        true
+    }
+
+    /// `NO_CACHE` skips the cache lookup but still stores the answer, so the
+    /// cached RRset is overwritten by the upstream one without a flush.
+    async fn replace(&self, rrset: &RRsetKey) -> Result<RepopulateOutcome, BackendError> {
+        self.resolve(rrset, "{'NO_CACHE'}").await
+    }
+}
+
+#[derive(Serialize)]
+struct CacheClearRequest<'a> {
+    name: &'a str,
+    #[serde(rename = "exact-name")]
+    exact_name: bool,
+    #[serde(rename = "rr-type", skip_serializing_if = "Option::is_none")]
+    rr_type: Option<String>,
+}
+
+/// Knot Resolver 6 through the management HTTP API.
+pub struct KnotManagerClient {
+    url: String,
+    http: reqwest::Client,
+}
+
+impl KnotManagerClient {
+    pub fn from_settings(settings: &KnotSettings) -> Self {
+        KnotManagerClient {
+            url: settings.manager_url.trim_end_matches('/').to_string(),
+            http: reqwest::Client::new(),
+        }
+    }
+
+    async fn clear(
+        &self,
+        request: &CacheClearRequest<'_>,
+    ) -> Result<InvalidateOutcome, BackendError> {
+        let response = self
+            .http
+            .post(format!("{}/cache/clear", self.url))
+            .json(request)
+            .send()
+            .await
+            .map_err(|e| BackendError::Protocol(e.to_string()))?;
+        let status = response.status();
+        let output = response
+            .text()
+            .await
+            .map_err(|e| BackendError::Protocol(e.to_string()))?;
+        match status.is_success() {
+            true => Ok(InvalidateOutcome { output }),
+            false => Err(BackendError::Protocol(format!(
+                "{}: {}",
+                status,
+                output.trim()
+            ))),
+        }
+    }
+}
+
+#[async_trait]
+impl DnsInvalidate for KnotManagerClient {
+    fn name(&self) -> &'static str {
+        "knot_manager"
+    }
+
+    async fn invalidate(&self, rrset: &RRsetKey) -> Result<InvalidateOutcome, BackendError> {
+        self.clear(&CacheClearRequest {
+            name: &rrset.domain_name,
+            exact_name: true,
+            rr_type: match rrset.record_type {
+                RecordType::Other => None,
+                _ => Some(rrset.record_type.form_for_command_line_arg().to_uppercase()),
+            },
+        })
+        .await
+    }
+
+    async fn flush_zone(&self, suffix: &str) -> Result<InvalidateOutcome, BackendError> {
+        self.clear(&CacheClearRequest {
+            name: suffix,
+            exact_name: false,
+            rr_type: None,
+        })
+        .await
+    }
+}
@@ -1,37 +1,73 @@
 use anyhow::{Context as _, Ok};
+use audit::AuditLog;
+use chains::AliasChains;
 use chrono::{self};
+use control::{handle_signals, Control};
+use dry_run::{DryRun, PlanLog};
 use event_manip::aggregate_dns_answers;
 use event_manip::purge_dns_records;
-use event_manip::DigRepopulator;
-use event_manip::DockerDigRepopulator;
-use event_manip::DockerUnboundInvalidator;
-use event_manip::UnboundInvalidator;
+use event_manip::Dispatch;
+use expiry_queue::ExpiryQueue;
+use fleet::{packet_addresses, udp_destination_port, Fleet, ResolverTarget};
 use lazy_static::lazy_static;
+use policy::PolicyTable;
+use popularity::PopularityTracker;
 use prometheus::register_gauge;
+use prometheus::register_histogram_vec;
+use prometheus::register_int_counter;
 use prometheus::register_int_counter_vec;
+use prometheus::register_int_gauge;
+use prometheus::register_int_gauge_vec;
 use prometheus::Gauge;
+use prometheus::HistogramVec;
+use prometheus::IntCounter;
 use prometheus::IntCounterVec;
+use prometheus::IntGauge;
+use prometheus::IntGaugeVec;
 use settings::settings;
 use sqlx::PgPool;
-use std::collections::BinaryHeap;
 use std::env;
 use std::sync::Arc;
 use std::{ptr, slice};
 use warp::Filter;
 use warp_handlers::metrics;
-use warp_handlers::{get_universe, with_universe};
+use warp_handlers::{
+    control_purger, flush_zone, get_planned_actions, get_purger_state, get_universe,
+    invalidate_record, refresh_record, with_alias_chains, with_manual_actions,
+    with_planned_actions, with_purger_control, with_universe, ManualActions,
+};
 
 use aya::programs::{Xdp, XdpFlags};
 use clap::Parser;
-use log::{debug, info, warn};
+use log::{debug, error, info, warn};
 use tokio::io::unix::AsyncFd;
 use tokio::join;
 use tokio::sync::{mpsc, watch, RwLock};
 
+mod audit;
+mod chains;
+mod control;
+mod dns_query;
+mod dry_run;
 mod event_manip;
+mod expiry_queue;
+mod fleet;
+mod knot;
+mod manual;
+mod outage;
+mod own_queries;
 mod persistence;
+mod policy;
+mod popularity;
+mod powerdns;
+mod public_suffix;
+mod resilience;
+mod rndc;
 mod settings;
 mod structs;
+mod token_bucket;
+mod unbound_control;
+mod upstream_limit;
 mod warp_handlers;
 use structs::{DnsAnswer, DnsResponse};
 
@@ -41,6 +77,9 @@ struct Opt {
     iface: String,
     #[clap(short, long, default_value = "3030")]
     port: u16,
+    /// Only log and record what the purger would flush and re-query.
+    #[clap(long)]
+    dry_run: bool,
 }
 
 lazy_static! {
@@ -67,6 +106,102 @@ lazy_static! {
         "Number of failed record maniuplations",
     )
     .unwrap();
+    static ref TRACKED_RECORDS: IntGauge = register_int_gauge!(
+        "tracked_records",
+        "Number of distinct records kept in memory",
+    )
+    .unwrap();
+    static ref EVICTED_RECORDS_COUNTER_VEC: IntCounterVec = register_int_counter_vec!(
+        "evicted_records",
+        "Number of records dropped from memory",
+        &["reason"]
+    )
+    .unwrap();
+    static ref ABUSIVE_PARENT_DOMAINS: IntGauge = register_int_gauge!(
+        "abusive_parent_domains",
+        "Number of parent domains no longer tracked for having too many records",
+    )
+    .unwrap();
+    static ref BACKEND_ACTION_DURATION_HISTOGRAM_VEC: HistogramVec = register_histogram_vec!(
+        "backend_action_duration_seconds",
+        "Time taken by invalidation and repopulation backends",
+        &["action", "backend", "target", "result"]
+    )
+    .unwrap();
+    static ref RESOLVER_UP_VEC: IntGaugeVec = register_int_gauge_vec!(
+        "resolver_up",
+        "Whether the last action on a resolver of the fleet succeeded",
+        &["target"]
+    )
+    .unwrap();
+    static ref CIRCUIT_BREAKER_STATE_VEC: IntGaugeVec = register_int_gauge_vec!(
+        "circuit_breaker_state",
+        "Circuit breaker per resolver: 0 closed, 1 open, 2 half-open",
+        &["target"]
+    )
+    .unwrap();
+    static ref RETRIED_ACTIONS_COUNTER_VEC: IntCounterVec = register_int_counter_vec!(
+        "retried_actions",
+        "Number of backend actions retried after a failure",
+        &["action", "target"]
+    )
+    .unwrap();
+    static ref REQUEUED_RECORDS_COUNTER: IntCounter = register_int_counter!(
+        "requeued_records",
+        "Number of records put back in the queue after failing to refresh",
+    )
+    .unwrap();
+    static ref ZONES_IN_OUTAGE: IntGauge = register_int_gauge!(
+        "zones_in_outage",
+        "Number of zones no longer flushed because refreshing them fails",
+    )
+    .unwrap();
+    static ref ZONE_OUTAGES_COUNTER: IntCounter = register_int_counter!(
+        "zone_outages",
+        "Number of times a zone was found failing to refresh",
+    )
+    .unwrap();
+    static ref THROTTLED_REFRESHES_COUNTER_VEC: IntCounterVec = register_int_counter_vec!(
+        "throttled_refreshes",
+        "Number of times a due refresh was deferred by a rate limit",
+        &["scope"]
+    )
+    .unwrap();
+    static ref API_REQUESTS_COUNTER_VEC: IntCounterVec = register_int_counter_vec!(
+        "api_requests",
+        "Number of manual action requests by endpoint and response status",
+        &["endpoint", "status"]
+    )
+    .unwrap();
+    static ref PURGER_STATE: IntGauge = register_int_gauge!(
+        "purger_state",
+        "Purger state: 0 running, 1 paused, 2 draining, 3 drained",
+    )
+    .unwrap();
+    static ref PLANNED_ACTIONS_COUNTER_VEC: IntCounterVec = register_int_counter_vec!(
+        "planned_actions",
+        "Number of actions planned but not taken in dry-run mode",
+        &["action", "target"]
+    )
+    .unwrap();
+    static ref REFRESH_VERIFIED_COUNTER_VEC: IntCounterVec = register_int_counter_vec!(
+        "refresh_verified",
+        "Number of refreshes the resolver was found to hold fresh",
+        &["target"]
+    )
+    .unwrap();
+    static ref REFRESH_STALE_COUNTER_VEC: IntCounterVec = register_int_counter_vec!(
+        "refresh_stale",
+        "Number of refreshes the resolver was found not to hold fresh",
+        &["target"]
+    )
+    .unwrap();
+    static ref DNS_QUERY_DURATION_HISTOGRAM_VEC: HistogramVec = register_histogram_vec!(
+        "dns_query_duration_seconds",
+        "Latency of queries sent to the resolver",
+        &["transport"]
+    )
+    .unwrap();
 }
 
 #[tokio::main]
@@ -100,7 +235,11 @@ async fn main() -> anyhow::Result<()> {
         // This can happen if you remove all log statements from your eBPF program.
         warn!("failed to initialize eBPF logger: {}", e);
     }
-    let Opt { iface, port } = opt;
+    let Opt {
+        iface,
+        port,
+        dry_run,
+    } = opt;
     let program: &mut Xdp = ebpf.program_mut("koroz").unwrap().try_into()?;
     program.load()?;
     program.attach(&iface, XdpFlags::SKB_MODE)
@@ -119,8 +258,39 @@ async fn main() -> anyhow::Result<()> {
     let (t_event, r_event_collector): (mpsc::Sender<DnsResponse>, mpsc::Receiver<DnsResponse>) =
         mpsc::channel(20);
 
-    let dns_answers = Arc::new(RwLock::new(BinaryHeap::new()));
-    let last_seen_answer = Arc::new(RwLock::new(std::collections::HashMap::new()));
+    match &settings().public_suffix_list {
+        Some(path) => public_suffix::load(path)?,
+        None => {
+            warn!("No public_suffix_list configured: parent domains are not flagged as abusive and outages are tracked per name")
+        }
+    }
+    let policies =
+        Arc::new(PolicyTable::from_settings(&settings()).context("invalid refresh policies")?);
+    let dns_answers = Arc::new(RwLock::new(ExpiryQueue::new(
+        settings().max_tracked_records,
+        settings().max_records_per_parent_domain,
+        chrono::TimeDelta::seconds(settings().abusive_parent_domain_seconds as i64),
+        Arc::clone(&policies),
+    )));
+    let alias_chains = Arc::new(RwLock::new(AliasChains::new(
+        settings().max_tracked_records,
+    )));
+    let popularity = Arc::new(RwLock::new(PopularityTracker::new(
+        settings().popularity_half_life,
+        settings().max_tracked_records,
+    )));
+
+    let planned_actions = Arc::new(RwLock::new(PlanLog::default()));
+    let dry_run = (dry_run || settings().dry_run.enabled).then(|| DryRun {
+        planned: Arc::clone(&planned_actions),
+        pool: settings().dry_run.store_in_database.then(|| pool.clone()),
+    });
+    if dry_run.is_some() {
+        warn!("Dry run: resolvers will not be flushed or queried");
+    }
+    let audit = AuditLog::spawn(&settings().audit, pool.clone())
+        .await
+        .context("failed to set up the audit log")?;
 
     let read_buffer = tokio::spawn(async move {
         let mut rx = rx.clone();
@@ -139,8 +309,11 @@ async fn main() -> anyhow::Result<()> {
                         let data = unsafe { slice::from_raw_parts(ptr.byte_add(2).byte_add(8), size.into()) };
                         let reading_time = chrono::offset::Utc::now();
 
+                        let (source_address, destination_address) = packet_addresses(data).unzip();
+
                         if let std::result::Result::Ok(response_packet) = dns_parser::Packet::parse(&data[42_usize..size as usize]) {
-                            t_event.send(response_packet.answers.into_iter().map(|answer| (answer, reading_time)).map(DnsAnswer::from).collect()).await.unwrap();
+                            let answers = response_packet.answers.into_iter().map(|answer| (answer, reading_time)).filter_map(|answer| DnsAnswer::try_from(answer).ok()).map(|answer| DnsAnswer { source_address, destination_address, ..answer }).collect();
+                            t_event.send(DnsResponse { id: response_packet.header.id, destination_port: udp_destination_port(data), answers }).await.unwrap();
                         }
                     }
 
@@ -157,21 +330,88 @@ async fn main() -> anyhow::Result<()> {
 
     let collector = {
         let received_data = Arc::clone(&dns_answers);
-        let last_seen_answer = Arc::clone(&last_seen_answer);
+        let alias_chains = Arc::clone(&alias_chains);
+        let popularity = Arc::clone(&popularity);
 
         tokio::spawn(async move {
-            aggregate_dns_answers(r_event_collector, received_data, last_seen_answer, pool).await;
+            aggregate_dns_answers(
+                r_event_collector,
+                received_data,
+                alias_chains,
+                popularity,
+                pool,
+            )
+            .await;
         })
     };
 
+    let fleet = Arc::new(Fleet::new(
+        settings()
+            .resolver_targets()
+            .iter()
+            .map(|resolver| ResolverTarget::from_settings(resolver, &dns_answers, &alias_chains))
+            .collect::<anyhow::Result<Vec<_>>>()?,
+        settings().fleet_dispatch,
+    ));
+    let dispatch = Dispatch::new(fleet, dry_run, audit);
+    let control = Arc::new(Control::default());
+
     let get_universe_route = warp::path("universe")
         .and(warp::get())
         .and(with_universe(dns_answers.clone()))
+        .and(with_alias_chains(alias_chains.clone()))
         .and_then(get_universe);
 
+    let planned_actions_route = warp::path("planned")
+        .and(warp::get())
+        .and(with_planned_actions(planned_actions))
+        .and_then(get_planned_actions);
+
     let metrics_route = warp::path("metrics").and(warp::get()).and_then(metrics);
 
-    let warp_routes = warp::get().and(get_universe_route).or(metrics_route);
+    let manual_actions = ManualActions {
+        dispatch: dispatch.clone(),
+        policies: Arc::clone(&policies),
+    };
+    let invalidate_route = warp::path!("records" / String / String / "invalidate")
+        .and(warp::post())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(with_manual_actions(manual_actions.clone()))
+        .and_then(invalidate_record);
+
+    let refresh_route = warp::path!("records" / String / String / "refresh")
+        .and(warp::post())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(with_manual_actions(manual_actions.clone()))
+        .and_then(refresh_record);
+
+    let flush_zone_route = warp::path!("zones" / String / "flush")
+        .and(warp::post())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(with_manual_actions(manual_actions))
+        .and_then(flush_zone);
+
+    let purger_state_route = warp::path("purger")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(with_purger_control(Arc::clone(&control)))
+        .and_then(get_purger_state);
+
+    let purger_control_route = warp::path!("purger" / String)
+        .and(warp::post())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(with_purger_control(Arc::clone(&control)))
+        .and_then(control_purger);
+
+    let warp_routes = warp::get()
+        .and(get_universe_route)
+        .or(planned_actions_route)
+        .or(metrics_route)
+        .or(invalidate_route)
+        .or(refresh_route)
+        .or(flush_zone_route)
+        .or(purger_state_route)
+        .or(purger_control_route);
 
     let warp_handle = {
         tokio::spawn(async move {
@@ -179,33 +419,35 @@ async fn main() -> anyhow::Result<()> {
         })
     };
 
+    let signals = {
+        let control = Arc::clone(&control);
+
+        tokio::spawn(async move {
+            if let Err(e) = handle_signals(control).await {
+                error!("Failed to listen for signals: {}", e);
+            }
+        })
+    };
+
     let refresher = {
         let dns_answers = Arc::clone(&dns_answers);
-        let last_seen_answer = Arc::clone(&last_seen_answer);
-
-        match settings().we_running_docker {
-            true => tokio::spawn(async move {
-                purge_dns_records(
-                    dns_answers,
-                    DockerUnboundInvalidator,
-                    DockerDigRepopulator,
-                    last_seen_answer,
-                )
-                .await;
-            }),
-            false => tokio::spawn(async move {
-                purge_dns_records(
-                    dns_answers,
-                    UnboundInvalidator,
-                    DigRepopulator,
-                    last_seen_answer,
-                )
-                .await
-            }),
-        }
+        let alias_chains = Arc::clone(&alias_chains);
+        let popularity = Arc::clone(&popularity);
+
+        tokio::spawn(async move {
+            purge_dns_records(
+                dns_answers,
+                dispatch,
+                policies,
+                alias_chains,
+                popularity,
+                control,
+            )
+            .await
+        })
     };
 
-    join!(collector, read_buffer, warp_handle, refresher);
+    join!(collector, read_buffer, warp_handle, signals, refresher);
     info!("Exiting...");
     Ok(())
 }
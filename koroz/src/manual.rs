@@ -0,0 +1,179 @@
+use std::{future::Future, time::Instant};
+
+use futures::future::join_all;
+use serde::Serialize;
+
+use crate::{
+    audit::{ActionReport, Trigger},
+    event_manip::{record_action_result, refresh_action, BackendError, Dispatch},
+    fleet::ResolverTarget,
+    settings::RefreshStrategy,
+    structs::{RRsetKey, RecordType},
+};
+
+/// What a manual action did on one resolver.
+#[derive(Debug, Serialize)]
+pub struct ManualOutcome {
+    pub target: String,
+    pub action: &'static str,
+    pub backend: &'static str,
+    /// `success`, `failure`, or `planned` in dry-run mode.
+    pub outcome: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl ManualOutcome {
+    pub fn failed(&self) -> bool {
+        self.outcome == "failure"
+    }
+}
+
+/// Runs `work` once, without the purger's retries, so the caller sees what
+/// the backend answered.
+async fn act(
+    dispatch: &Dispatch,
+    target: &ResolverTarget,
+    action: &'static str,
+    backend: &'static str,
+    rrset: &RRsetKey,
+    work: impl Future<Output = Result<String, BackendError>>,
+) -> ManualOutcome {
+    let mut outcome = ManualOutcome {
+        target: target.name.clone(),
+        action,
+        backend,
+        outcome: "planned",
+        output: None,
+        error: None,
+    };
+    if let Some(dry_run) = &dispatch.dry_run {
+        dry_run.plan(action, &target.name, rrset).await;
+        return outcome;
+    }
+
+    let permit = dispatch.in_flight.acquire().await.unwrap();
+    let started = Instant::now();
+    let result = work.await;
+    let elapsed = started.elapsed();
+    drop(permit);
+
+    target.record_outcome(result.is_ok());
+    let report = ActionReport {
+        action,
+        backend,
+        target: &target.name,
+        rrset,
+        trigger: Trigger::Api,
+        elapsed,
+    };
+    record_action_result(&report, &result, &dispatch.audit);
+    match result {
+        Ok(output) => {
+            outcome.outcome = "success";
+            outcome.output = Some(output.trim().to_string()).filter(|output| !output.is_empty());
+        }
+        Err(e) => {
+            outcome.outcome = "failure";
+            outcome.error = Some(e.to_string());
+        }
+    }
+    outcome
+}
+
+async fn invalidate_on(
+    dispatch: &Dispatch,
+    target: &ResolverTarget,
+    rrset: &RRsetKey,
+) -> ManualOutcome {
+    act(
+        dispatch,
+        target,
+        "invalidate",
+        target.invalidator.name(),
+        rrset,
+        async {
+            target
+                .invalidator
+                .invalidate(rrset)
+                .await
+                .map(|outcome| outcome.output)
+        },
+    )
+    .await
+}
+
+/// Flushes `rrset` on every resolver.
+pub async fn invalidate(dispatch: &Dispatch, rrset: &RRsetKey) -> Vec<ManualOutcome> {
+    join_all(
+        dispatch
+            .fleet
+            .targets()
+            .iter()
+            .map(|target| invalidate_on(dispatch, target, rrset)),
+    )
+    .await
+}
+
+/// Refreshes `rrset` on every resolver the way the purger would with
+/// `strategy`.
+pub async fn refresh(
+    dispatch: &Dispatch,
+    rrset: &RRsetKey,
+    strategy: RefreshStrategy,
+) -> Vec<ManualOutcome> {
+    let refreshes = dispatch.fleet.targets().iter().map(|target| async move {
+        let mut outcomes = vec![];
+        if strategy == RefreshStrategy::FlushThenQuery {
+            outcomes.push(invalidate_on(dispatch, target, rrset).await);
+        }
+        let refreshed = act(
+            dispatch,
+            target,
+            refresh_action(strategy),
+            target.repopulator.name(),
+            rrset,
+            async {
+                let outcome = match strategy {
+                    RefreshStrategy::Replace => target.repopulator.replace(rrset).await,
+                    _ => target.repopulator.repopulate(rrset).await,
+                }?;
+                Ok(outcome
+                    .ttl
+                    .map(|ttl| format!("fresh TTL {}s", ttl))
+                    .unwrap_or_default())
+            },
+        )
+        .await;
+        outcomes.push(refreshed);
+        outcomes
+    });
+    join_all(refreshes).await.into_iter().flatten().collect()
+}
+
+/// Flushes everything cached at and below `suffix` on every resolver.
+pub async fn flush_zone(dispatch: &Dispatch, suffix: &str) -> Vec<ManualOutcome> {
+    let rrset = RRsetKey {
+        domain_name: suffix.to_string(),
+        record_type: RecordType::Other,
+    };
+    join_all(dispatch.fleet.targets().iter().map(|target| {
+        act(
+            dispatch,
+            target,
+            "flush_zone",
+            target.invalidator.name(),
+            &rrset,
+            async {
+                target
+                    .invalidator
+                    .flush_zone(suffix)
+                    .await
+                    .map(|outcome| outcome.output)
+            },
+        )
+    }))
+    .await
+}
@@ -0,0 +1,149 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use log::{info, warn};
+
+use crate::{
+    event_manip::RepopulateOutcome,
+    public_suffix::{self, registrable_domain},
+    settings::OutageSettings,
+    structs::DnsAnswer,
+    ZONES_IN_OUTAGE, ZONE_OUTAGES_COUNTER,
+};
+
+/// The zone `domain_name` is held under: its registrable domain, or the name
+/// itself without a public suffix list, lest a few failing `co.uk` sites put
+/// all of `co.uk` in outage.
+pub fn zone(domain_name: &str) -> String {
+    if public_suffix::is_loaded() {
+        registrable_domain(domain_name)
+    } else {
+        domain_name.trim_end_matches('.').to_lowercase()
+    }
+}
+
+#[derive(Debug)]
+struct Outage {
+    since: Instant,
+    last_probe: Instant,
+    /// Records left to the resolver's serve-expired in the meantime.
+    held: Vec<DnsAnswer>,
+}
+
+/// Zones, by registrable domain (see `zone`), whose authoritative servers fail to answer
+/// refreshes. Flushing them would swap good cached answers for SERVFAIL, so their records are held
+/// back, and only one of them is refreshed now and then as a probe until the
+/// zone answers again.
+#[derive(Debug)]
+pub struct OutageTracker {
+    enabled: bool,
+    failures: HashMap<String, u32>,
+    outages: HashMap<String, Outage>,
+    failure_threshold: u32,
+    probe_interval: Duration,
+    stale_reply_ttl: u32,
+}
+
+impl OutageTracker {
+    pub fn from_settings(settings: &OutageSettings) -> Self {
+        OutageTracker {
+            enabled: settings.enabled,
+            failures: HashMap::new(),
+            outages: HashMap::new(),
+            failure_threshold: settings.failure_threshold.max(1),
+            probe_interval: Duration::from_secs(settings.probe_interval),
+            stale_reply_ttl: settings.stale_reply_ttl,
+        }
+    }
+
+    /// Whether an answer the resolver may have taken from its cache, stale
+    /// or not, shows the zone answering again. Only a TTL fresher than the
+    /// one stale answers get does, NODATA or an unknown TTL does not.
+    pub fn answered_upstream(&self, outcome: &RepopulateOutcome) -> bool {
+        outcome.ttl.is_some_and(|ttl| ttl > self.stale_reply_ttl)
+    }
+
+    /// Keeps the answer aside when its zone is in outage, hands it back
+    /// otherwise.
+    pub fn hold(&mut self, answer: DnsAnswer) -> Option<DnsAnswer> {
+        match self.outages.get_mut(&zone(&answer.domain_name)) {
+            Some(outage) => {
+                outage.held.push(answer);
+                None
+            }
+            None => Some(answer),
+        }
+    }
+
+    /// When the next probe is due.
+    pub fn next_probe(&self) -> Option<Instant> {
+        self.outages
+            .values()
+            .filter(|outage| !outage.held.is_empty())
+            .map(|outage| outage.last_probe + self.probe_interval)
+            .min()
+    }
+
+    /// A held record for each zone in outage due for a probe.
+    pub fn probes(&mut self, now: Instant) -> Vec<DnsAnswer> {
+        let expired_before = Utc::now();
+        let mut probes = vec![];
+        for outage in self.outages.values_mut() {
+            outage
+                .held
+                .retain(|answer| answer.expiration_time() > expired_before);
+            if now.duration_since(outage.last_probe) < self.probe_interval {
+                continue;
+            }
+            if let Some(answer) = outage.held.pop() {
+                outage.last_probe = now;
+                probes.push(answer);
+            }
+        }
+        probes
+    }
+
+    /// Takes the outcome of a refresh in `zone` into account. Returns the
+    /// held records when it ends an outage.
+    pub fn record(&mut self, zone: &str, success: bool, now: Instant) -> Vec<DnsAnswer> {
+        let mut released = vec![];
+        if !self.enabled {
+            return released;
+        }
+        if success {
+            self.failures.remove(zone);
+            if let Some(outage) = self.outages.remove(zone) {
+                info!(
+                    "{} answers again after {:?}, resuming refreshes",
+                    zone,
+                    now.duration_since(outage.since)
+                );
+                released = outage.held;
+            }
+        } else if !self.outages.contains_key(zone) {
+            let failures = self.failures.entry(zone.to_string()).or_default();
+            *failures += 1;
+            if *failures >= self.failure_threshold {
+                warn!(
+                    "{} failed to refresh {} times in a row, no longer flushing it until it answers again",
+                    zone, failures
+                );
+                self.failures.remove(zone);
+                self.outages.insert(
+                    zone.to_string(),
+                    Outage {
+                        since: now,
+                        last_probe: now,
+                        held: vec![],
+                    },
+                );
+                ZONE_OUTAGES_COUNTER.inc();
+            }
+        }
+        ZONES_IN_OUTAGE.set(self.outages.len() as i64);
+        released
+    }
+}
@@ -0,0 +1,115 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use crate::structs::DnsResponse;
+
+// Captured responses reach the collector a little after the query that asked
+// for them returned.
+const CAPTURE_LAG: Duration = Duration::from_secs(5);
+
+fn queries() -> &'static Mutex<HashMap<OwnQuery, Entry>> {
+    static QUERIES: OnceLock<Mutex<HashMap<OwnQuery, Entry>>> = OnceLock::new();
+    QUERIES.get_or_init(Default::default)
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+enum OwnQuery {
+    /// Sent from a socket of ours: its answer comes back to that port with
+    /// the query's id.
+    Socket { port: u16, id: u16 },
+    /// Sent by a process koroz runs, such as `dig`, whose port and id it
+    /// never learns. Every answer for the name counts as its own meanwhile.
+    Name(String),
+}
+
+#[derive(Debug)]
+struct Entry {
+    in_flight: usize,
+    finished: Instant,
+}
+
+/// A query koroz sent to a resolver, whose answer is no client lookup and
+/// must not make the name any more popular. Answers captured until shortly
+/// after it is dropped are recognized as its own.
+#[derive(Debug)]
+pub struct InFlight(OwnQuery);
+
+impl InFlight {
+    fn start(query: OwnQuery) -> Self {
+        let mut queries = queries().lock().unwrap();
+        let entry = queries.entry(query.clone()).or_insert(Entry {
+            in_flight: 0,
+            finished: Instant::now(),
+        });
+        entry.in_flight += 1;
+        InFlight(query)
+    }
+
+    pub fn socket(port: u16, id: u16) -> Self {
+        InFlight::start(OwnQuery::Socket { port, id })
+    }
+
+    pub fn name(domain_name: &str) -> Self {
+        InFlight::start(OwnQuery::Name(normalize(domain_name)))
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if let Some(entry) = queries().lock().unwrap().get_mut(&self.0) {
+            entry.in_flight -= 1;
+            entry.finished = Instant::now();
+        }
+    }
+}
+
+fn normalize(domain_name: &str) -> String {
+    domain_name.trim_end_matches('.').to_lowercase()
+}
+
+/// Whether `response` answers one of koroz's own queries rather than a
+/// client's.
+pub fn is_own(response: &DnsResponse) -> bool {
+    let mut queries = queries().lock().unwrap();
+    let now = Instant::now();
+    queries.retain(|_, entry| entry.in_flight > 0 || now - entry.finished < CAPTURE_LAG);
+    let by_socket = response.destination_port.is_some_and(|port| {
+        queries.contains_key(&OwnQuery::Socket {
+            port,
+            id: response.id,
+        })
+    });
+    // The first answer is for the name asked, or the alias leading to it.
+    let by_name = response.answers.first().is_some_and(|answer| {
+        queries.contains_key(&OwnQuery::Name(normalize(&answer.domain_name)))
+    });
+    by_socket || by_name
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(id: u16, destination_port: u16) -> DnsResponse {
+        DnsResponse {
+            id,
+            destination_port: Some(destination_port),
+            answers: vec![],
+        }
+    }
+
+    #[test]
+    fn recognizes_answers_to_its_own_socket_until_after_the_query() {
+        let query = InFlight::socket(40_000, 7);
+        assert!(is_own(&response(7, 40_000)));
+        assert!(!is_own(&response(8, 40_000)));
+        assert!(!is_own(&response(7, 40_001)));
+
+        // Captured a moment after the query returned.
+        drop(query);
+        assert!(is_own(&response(7, 40_000)));
+    }
+}
@@ -1,5 +1,7 @@
 use sqlx::PgPool;
 
+use crate::audit::AuditEntry;
+use crate::dry_run::PlannedAction;
 use crate::structs::DnsAnswer;
 
 impl DnsAnswer {
@@ -22,3 +24,50 @@ impl DnsAnswer {
         Ok(())
     }
 }
+
+impl PlannedAction {
+    pub async fn insert(&self, pool: &PgPool) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+            INSERT INTO planned_actions (planned_at, action, target, domain_name, record_type)
+            VALUES ($1, $2, $3, $4, $5)
+            "#,
+            self.planned_at,
+            self.action,
+            self.target,
+            self.domain_name,
+            self.record_type.form_for_command_line_arg()
+        )
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+}
+
+impl AuditEntry {
+    pub async fn insert(&self, pool: &PgPool) -> Result<(), sqlx::Error> {
+        sqlx::query!(
+            r#"
+            INSERT INTO cache_actions (
+                at, domain_name, record_type, action, backend, target, trigger,
+                duration_ms, outcome, exit_code, error
+            )
+            VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)
+            "#,
+            self.timestamp,
+            self.domain_name,
+            self.record_type.form_for_command_line_arg(),
+            self.action,
+            self.backend,
+            self.target,
+            self.trigger.as_str(),
+            self.duration_ms,
+            self.outcome,
+            self.exit_code,
+            self.error
+        )
+        .execute(pool)
+        .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,214 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use anyhow::{bail, Context as _};
+use chrono::TimeDelta;
+use regex::{Regex, RegexBuilder};
+
+use crate::{
+    settings::{PolicySettings, RefreshMode, RefreshStrategy, Settings},
+    structs::{DnsAnswer, RRsetKey, RecordType},
+    token_bucket::TokenBucket,
+    upstream_limit::{Throttle, UpstreamLimiter},
+};
+
+pub type Policies = Arc<PolicyTable>;
+
+#[derive(Debug, Clone, Copy)]
+enum LeadTime {
+    Seconds(i64),
+    Percent(f64),
+}
+
+/// A compiled `[[policies]]` rule.
+#[derive(Debug)]
+pub struct Policy {
+    matcher: Regex,
+    record_types: Vec<RecordType>,
+    refresh: Option<RefreshMode>,
+    lead_time: Option<LeadTime>,
+    min_popularity: Option<f64>,
+    limiter: Option<Mutex<TokenBucket>>,
+    /// Index of the resolver in the fleet.
+    pub target: Option<usize>,
+    pub strategy: Option<RefreshStrategy>,
+}
+
+fn domain_pattern(settings: &PolicySettings) -> anyhow::Result<String> {
+    match (&settings.suffix, &settings.glob, &settings.regex) {
+        (Some(suffix), None, None) => Ok(format!(
+            r"(^|\.){}\.?$",
+            regex::escape(suffix.trim_end_matches('.'))
+        )),
+        (None, Some(glob), None) => Ok(format!(
+            r"^{}\.?$",
+            regex::escape(glob.trim_end_matches('.')).replace(r"\*", ".*")
+        )),
+        (None, None, Some(regex)) => Ok(regex.clone()),
+        _ => bail!("a policy needs exactly one of suffix, glob and regex"),
+    }
+}
+
+impl Policy {
+    fn from_settings(settings: &PolicySettings, resolvers: &[String]) -> anyhow::Result<Self> {
+        let pattern = domain_pattern(settings)?;
+        let matcher = RegexBuilder::new(&pattern)
+            .case_insensitive(true)
+            .build()
+            .with_context(|| format!("invalid policy pattern {}", pattern))?;
+        let lead_time = match (settings.lead_time_seconds, settings.lead_time_percent) {
+            (Some(_), Some(_)) => {
+                bail!(
+                    "{}: lead_time_seconds and lead_time_percent are exclusive",
+                    pattern
+                )
+            }
+            (Some(seconds), None) => Some(LeadTime::Seconds(seconds)),
+            (None, Some(percent)) if (0.0..=100.0).contains(&percent) => {
+                Some(LeadTime::Percent(percent))
+            }
+            (None, Some(percent)) => {
+                bail!("{}: lead_time_percent {} is not 0-100", pattern, percent)
+            }
+            (None, None) => None,
+        };
+        let target = match &settings.target {
+            Some(name) => Some(
+                resolvers
+                    .iter()
+                    .position(|resolver| resolver == name)
+                    .with_context(|| format!("{}: no resolver named {}", pattern, name))?,
+            ),
+            None => None,
+        };
+        Ok(Policy {
+            matcher,
+            record_types: settings.record_types.clone(),
+            refresh: settings.refresh,
+            lead_time,
+            min_popularity: settings.min_popularity,
+            limiter: settings
+                .max_refresh_rate
+                .map(|rate| Mutex::new(TokenBucket::new(rate, rate))),
+            target,
+            strategy: settings.strategy,
+        })
+    }
+
+    fn matches(&self, rrset: &RRsetKey) -> bool {
+        (self.record_types.is_empty() || self.record_types.contains(&rrset.record_type))
+            && self.matcher.is_match(&rrset.domain_name)
+    }
+
+    /// Whether the rule, rather than the record's TTL alone, decides when or
+    /// whether it is refreshed.
+    pub fn overrides_schedule(&self) -> bool {
+        self.refresh == Some(RefreshMode::Always) || self.lead_time.is_some()
+    }
+}
+
+/// Refresh rules, the first one matching a record applies.
+#[derive(Debug)]
+pub struct PolicyTable {
+    rules: Vec<Policy>,
+    default_lead_time: i64,
+    default_min_popularity: f64,
+    upstream: Mutex<UpstreamLimiter>,
+}
+
+impl PolicyTable {
+    pub fn from_settings(settings: &Settings) -> anyhow::Result<Self> {
+        let resolvers: Vec<String> = settings
+            .resolver_targets()
+            .into_iter()
+            .map(|resolver| resolver.name)
+            .collect();
+        Ok(PolicyTable {
+            rules: settings
+                .policies
+                .iter()
+                .map(|policy| Policy::from_settings(policy, &resolvers))
+                .collect::<anyhow::Result<_>>()?,
+            default_lead_time: settings.min_time_to_expire_to_purge,
+            default_min_popularity: settings.min_popularity_to_refresh,
+            upstream: Mutex::new(
+                UpstreamLimiter::from_settings(&settings.upstream_limits)
+                    .context("invalid upstream limits")?,
+            ),
+        })
+    }
+
+    pub fn matching(&self, rrset: &RRsetKey) -> Option<&Policy> {
+        self.rules.iter().find(|policy| policy.matches(rrset))
+    }
+
+    /// How long before expiration the answer is due for a refresh.
+    pub fn lead_time(&self, answer: &DnsAnswer) -> TimeDelta {
+        let seconds = match self
+            .matching(&answer.rrset_key())
+            .and_then(|policy| policy.lead_time)
+        {
+            Some(LeadTime::Seconds(seconds)) => seconds,
+            Some(LeadTime::Percent(percent)) => (answer.ttl as f64 * percent / 100.0) as i64,
+            None => self.default_lead_time,
+        };
+        TimeDelta::seconds(seconds)
+    }
+
+    /// Records outside the TTL bounds are only tracked when a rule asks for
+    /// them to always be refreshed.
+    pub fn should_track(&self, answer: &DnsAnswer) -> bool {
+        answer.has_reasonable_ttl()
+            || self
+                .matching(&answer.rrset_key())
+                .is_some_and(|policy| policy.refresh == Some(RefreshMode::Always))
+    }
+
+    /// Takes `tokens` for a refresh of `rrset` from the upstream limits and
+    /// from its rule's, or from none of them. `Err` holds the limit that is
+    /// exhausted and how long until it has a token.
+    pub fn try_acquire(
+        &self,
+        rrset: &RRsetKey,
+        tokens: usize,
+        now: Instant,
+    ) -> Result<(), (Throttle, Duration)> {
+        let mut limiter = self
+            .matching(rrset)
+            .and_then(|policy| policy.limiter.as_ref())
+            .map(|limiter| limiter.lock().unwrap());
+        if let Some(limiter) = limiter.as_mut() {
+            if !limiter.has(tokens, now) {
+                return Err((Throttle::Policy, limiter.wait_time(now)));
+            }
+        }
+        self.upstream
+            .lock()
+            .unwrap()
+            .try_acquire(&rrset.domain_name, tokens, now)?;
+        if let Some(limiter) = limiter.as_mut() {
+            limiter.take(tokens);
+        }
+        Ok(())
+    }
+
+    pub fn forget_idle(&self, now: Instant) {
+        self.upstream.lock().unwrap().forget_idle(now);
+    }
+
+    pub fn should_refresh(&self, rrset: &RRsetKey, popularity: f64) -> bool {
+        let policy = self.matching(rrset);
+        match policy.and_then(|policy| policy.refresh) {
+            Some(RefreshMode::Never) => false,
+            Some(RefreshMode::Always) => true,
+            None => {
+                popularity
+                    >= policy
+                        .and_then(|policy| policy.min_popularity)
+                        .unwrap_or(self.default_min_popularity)
+            }
+        }
+    }
+}
@@ -0,0 +1,88 @@
+use std::{collections::HashMap, sync::Arc};
+
+use chrono::{DateTime, Utc};
+use tokio::sync::RwLock;
+
+use crate::{expiry_queue::EVICTION_HEADROOM, structs::RRsetKey};
+
+pub type Popularity = Arc<RwLock<PopularityTracker>>;
+
+// Scores that decayed below this are indistinguishable from never seen.
+const FORGET_BELOW_SCORE: f64 = 0.01;
+
+#[derive(Debug, Clone)]
+struct Score {
+    value: f64,
+    updated: DateTime<Utc>,
+}
+
+impl Score {
+    fn decayed(&self, now: DateTime<Utc>, half_life: f64) -> f64 {
+        let elapsed = now
+            .signed_duration_since(self.updated)
+            .num_milliseconds()
+            .max(0) as f64
+            / 1000.0;
+        self.value * (-elapsed * std::f64::consts::LN_2 / half_life).exp()
+    }
+}
+
+/// Exponentially decayed hit counter per (name, type).
+///
+/// Every hit adds one and the score halves every `half_life` seconds, so a
+/// name seen K times within the last half-life scores at least K / 2. Past
+/// `max_entries`, the lowest scores are dropped.
+#[derive(Debug)]
+pub struct PopularityTracker {
+    half_life: f64,
+    scores: HashMap<RRsetKey, Score>,
+    max_entries: usize,
+}
+
+impl PopularityTracker {
+    pub fn new(half_life_seconds: u64, max_entries: usize) -> Self {
+        PopularityTracker {
+            half_life: half_life_seconds.max(1) as f64,
+            scores: HashMap::new(),
+            max_entries,
+        }
+    }
+
+    pub fn hit(&mut self, key: RRsetKey, at: DateTime<Utc>) {
+        let value = match self.scores.get(&key) {
+            Some(score) => score.decayed(at, self.half_life) + 1.0,
+            None => 1.0,
+        };
+        self.scores.insert(key, Score { value, updated: at });
+        if self.scores.len() > self.max_entries {
+            self.evict_lowest(at);
+        }
+    }
+
+    fn evict_lowest(&mut self, now: DateTime<Utc>) {
+        let keep = (self.max_entries as f64 * EVICTION_HEADROOM) as usize;
+        let mut candidates: Vec<(f64, RRsetKey)> = self
+            .scores
+            .iter()
+            .map(|(key, score)| (score.decayed(now, self.half_life), key.clone()))
+            .collect();
+        candidates.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let evicted = candidates.len() - keep;
+        for (_, key) in candidates.into_iter().take(evicted) {
+            self.scores.remove(&key);
+        }
+    }
+
+    pub fn score(&self, key: &RRsetKey, now: DateTime<Utc>) -> f64 {
+        self.scores
+            .get(key)
+            .map(|score| score.decayed(now, self.half_life))
+            .unwrap_or(0.0)
+    }
+
+    pub fn forget_idle(&mut self, now: DateTime<Utc>) {
+        let half_life = self.half_life;
+        self.scores
+            .retain(|_, score| score.decayed(now, half_life) >= FORGET_BELOW_SCORE);
+    }
+}
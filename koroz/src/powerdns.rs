@@ -0,0 +1,204 @@
+use async_trait::async_trait;
+use serde::Deserialize;
+
+use crate::{
+    event_manip::{BackendError, DnsInvalidate, InvalidateOutcome},
+    settings::PowerDnsSettings,
+    structs::{RRsetKey, RecordType},
+};
+
+#[derive(Deserialize)]
+struct FlushResponse {
+    count: u64,
+    result: String,
+}
+
+/// PowerDNS Recursor through its REST API. Repopulation goes to the
+/// recursor's DNS port through `DnsQueryRepopulator`.
+pub struct PowerDnsInvalidator {
+    flush_url: String,
+    api_key: String,
+    http: reqwest::Client,
+}
+
+impl PowerDnsInvalidator {
+    pub fn from_settings(settings: &PowerDnsSettings) -> Self {
+        PowerDnsInvalidator {
+            flush_url: format!(
+                "{}/api/v1/servers/{}/cache/flush",
+                settings.api_url.trim_end_matches('/'),
+                settings.server_id
+            ),
+            api_key: settings.api_key.clone(),
+            http: reqwest::Client::new(),
+        }
+    }
+
+    pub async fn flush(
+        &self,
+        domain_name: &str,
+        subtree: bool,
+        record_type: Option<&RecordType>,
+    ) -> Result<InvalidateOutcome, BackendError> {
+        // The API wants fully qualified names.
+        let domain = format!("{}.", domain_name.trim_end_matches('.'));
+        let mut query = vec![("domain", domain), ("subtree", subtree.to_string())];
+        if let Some(record_type) = record_type.filter(|t| **t != RecordType::Other) {
+            query.push((
+                "type",
+                record_type.form_for_command_line_arg().to_uppercase(),
+            ));
+        }
+
+        let response = self
+            .http
+            .put(&self.flush_url)
+            .header("X-API-Key", &self.api_key)
+            .query(&query)
+            .send()
+            .await
+            .map_err(|e| BackendError::Protocol(e.to_string()))?;
+        let status = response.status();
+        if !status.is_success() {
+            let body = response.text().await.unwrap_or_default();
+            return Err(BackendError::Protocol(format!(
+                "{}: {}",
+                status,
+                body.trim()
+            )));
+        }
+        let flushed: FlushResponse = response
+            .json()
+            .await
+            .map_err(|e| BackendError::Protocol(e.to_string()))?;
+        Ok(InvalidateOutcome {
+            output: format!("{} ({} entries)", flushed.result, flushed.count),
+        })
+    }
+}
+
+#[async_trait]
+impl DnsInvalidate for PowerDnsInvalidator {
+    fn name(&self) -> &'static str {
+        "powerdns"
+    }
+
+    async fn invalidate(&self, rrset: &RRsetKey) -> Result<InvalidateOutcome, BackendError> {
+        self.flush(&rrset.domain_name, false, Some(&rrset.record_type))
+            .await
+    }
+
+    async fn flush_zone(&self, suffix: &str) -> Result<InvalidateOutcome, BackendError> {
+        self.flush(suffix, true, None).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::HashMap,
+        net::SocketAddr,
+        sync::{Arc, Mutex},
+    };
+
+    use warp::{http::StatusCode, Filter};
+
+    use super::*;
+
+    const API_KEY: &str = "secret";
+
+    #[derive(Debug, Clone)]
+    struct FlushRequest {
+        path: String,
+        query: HashMap<String, String>,
+        api_key: Option<String>,
+    }
+
+    /// A recursor API that flushes 3 entries for the right key and answers
+    /// 401 to any other.
+    fn stub_recursor() -> (SocketAddr, Arc<Mutex<Vec<FlushRequest>>>) {
+        let requests = Arc::new(Mutex::new(vec![]));
+        let seen = Arc::clone(&requests);
+        let flush = warp::put()
+            .and(warp::path::full())
+            .and(warp::query::<HashMap<String, String>>())
+            .and(warp::header::optional::<String>("x-api-key"))
+            .map(
+                move |path: warp::path::FullPath,
+                      query: HashMap<String, String>,
+                      api_key: Option<String>| {
+                    let authorized = api_key.as_deref() == Some(API_KEY);
+                    seen.lock().unwrap().push(FlushRequest {
+                        path: path.as_str().to_string(),
+                        query,
+                        api_key,
+                    });
+                    match authorized {
+                        true => warp::reply::with_status(
+                            r#"{"count": 3, "result": "Flushed cache."}"#,
+                            StatusCode::OK,
+                        ),
+                        false => warp::reply::with_status("Unauthorized", StatusCode::UNAUTHORIZED),
+                    }
+                },
+            );
+        let (address, server) = warp::serve(flush).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        (address, requests)
+    }
+
+    fn invalidator(address: SocketAddr, api_key: &str) -> PowerDnsInvalidator {
+        PowerDnsInvalidator::from_settings(&PowerDnsSettings {
+            api_url: format!("http://{}/", address),
+            api_key: api_key.to_string(),
+            server_id: "localhost".to_string(),
+            ..PowerDnsSettings::default()
+        })
+    }
+
+    #[tokio::test]
+    async fn flushes_one_rrset() {
+        let (address, requests) = stub_recursor();
+        let outcome = invalidator(address, API_KEY)
+            .flush("www.example.com", false, Some(&RecordType::AAAA))
+            .await
+            .unwrap();
+        assert_eq!(outcome.output, "Flushed cache. (3 entries)");
+
+        let request = requests.lock().unwrap()[0].clone();
+        assert_eq!(request.path, "/api/v1/servers/localhost/cache/flush");
+        assert_eq!(request.query["domain"], "www.example.com.");
+        assert_eq!(request.query["subtree"], "false");
+        assert_eq!(request.query["type"], "AAAA");
+        assert_eq!(request.api_key.as_deref(), Some(API_KEY));
+    }
+
+    #[tokio::test]
+    async fn flushes_a_zone_without_a_type() {
+        let (address, requests) = stub_recursor();
+        invalidator(address, API_KEY)
+            .flush_zone("example.com.")
+            .await
+            .unwrap();
+
+        let request = requests.lock().unwrap()[0].clone();
+        assert_eq!(request.query["domain"], "example.com.");
+        assert_eq!(request.query["subtree"], "true");
+        assert!(!request.query.contains_key("type"));
+    }
+
+    #[tokio::test]
+    async fn maps_error_statuses_to_protocol_errors() {
+        let (address, _) = stub_recursor();
+        let error = invalidator(address, "wrong")
+            .flush("www.example.com", false, None)
+            .await
+            .unwrap_err();
+        match error {
+            BackendError::Protocol(message) => {
+                assert_eq!(message, "401 Unauthorized: Unauthorized")
+            }
+            other => panic!("unexpected error {:?}", other),
+        }
+    }
+}
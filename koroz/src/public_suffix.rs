@@ -0,0 +1,56 @@
+use std::sync::OnceLock;
+
+use anyhow::Context as _;
+use publicsuffix::{List, Psl};
+
+static LIST: OnceLock<List> = OnceLock::new();
+
+/// Loads Mozilla's `public_suffix_list.dat` for `registrable_domain` and
+/// `is_public_suffix`.
+pub fn load(path: &str) -> anyhow::Result<()> {
+    let list = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read public suffix list {}", path))?
+        .parse::<List>()
+        .with_context(|| format!("invalid public suffix list {}", path))?;
+    let _ = LIST.set(list);
+    Ok(())
+}
+
+/// Without a list, registrable domains are guessed and `co.uk` passes for
+/// one, so whatever would lump sites together by them is left off.
+pub fn is_loaded() -> bool {
+    LIST.get().is_some()
+}
+
+fn normalize(domain_name: &str) -> String {
+    domain_name.trim_end_matches('.').to_lowercase()
+}
+
+/// `www.example.co.uk` -> `example.co.uk`. Without a public suffix list,
+/// the last two labels.
+pub fn registrable_domain(domain_name: &str) -> String {
+    let domain_name = normalize(domain_name);
+    if let Some(domain) = LIST
+        .get()
+        .and_then(|list| list.domain(domain_name.as_bytes()))
+    {
+        return String::from_utf8_lossy(domain.as_bytes()).into_owned();
+    }
+    let labels: Vec<&str> = domain_name.rsplitn(3, '.').collect();
+    match labels.as_slice() {
+        [tld, sld, _] => format!("{}.{}", sld, tld),
+        _ => domain_name,
+    }
+}
+
+/// `com` or `co.uk`, under which anyone registers names. Without a public
+/// suffix list, names without a dot.
+pub fn is_public_suffix(domain_name: &str) -> bool {
+    let domain_name = normalize(domain_name);
+    match LIST.get() {
+        Some(list) => list
+            .suffix(domain_name.as_bytes())
+            .is_some_and(|suffix| suffix.as_bytes() == domain_name.as_bytes()),
+        None => !domain_name.contains('.'),
+    }
+}
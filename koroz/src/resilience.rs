@@ -0,0 +1,116 @@
+use std::{
+    collections::{hash_map::RandomState, VecDeque},
+    hash::{BuildHasher, Hasher},
+    time::{Duration, Instant},
+};
+
+use crate::settings::ResilienceSettings;
+
+/// Delay before retry number `attempt` (starting at 0): exponential in the
+/// attempt, capped, with half of it randomized so that records failing
+/// together do not retry in lockstep.
+pub fn backoff_delay(attempt: u32, settings: &ResilienceSettings) -> Duration {
+    let ceiling = settings
+        .retry_base_delay_ms
+        .saturating_mul(1 << attempt.min(16))
+        .min(settings.retry_max_delay_ms);
+    let jitter = RandomState::new().build_hasher().finish() % (ceiling / 2 + 1);
+    Duration::from_millis(ceiling - ceiling / 2 + jitter)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakerState {
+    Closed,
+    Open,
+    /// The open period ran out, the next outcome decides whether to close.
+    HalfOpen,
+}
+
+impl BreakerState {
+    /// Value exported on the `circuit_breaker_state` gauge.
+    pub fn gauge_value(&self) -> i64 {
+        match self {
+            BreakerState::Closed => 0,
+            BreakerState::Open => 1,
+            BreakerState::HalfOpen => 2,
+        }
+    }
+}
+
+/// Trips once the share of failures among the last `window` actions on a
+/// resolver reaches `failure_rate`, and keeps it out of purging for
+/// `open_for` before letting a trial through.
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    outcomes: VecDeque<bool>,
+    window: usize,
+    failure_rate: f64,
+    open_for: Duration,
+    opened_at: Option<Instant>,
+    state: BreakerState,
+}
+
+impl CircuitBreaker {
+    pub fn from_settings(settings: &ResilienceSettings) -> Self {
+        CircuitBreaker {
+            outcomes: VecDeque::new(),
+            window: settings.breaker_window.max(1),
+            failure_rate: settings.breaker_failure_rate,
+            open_for: Duration::from_secs(settings.breaker_open_seconds),
+            opened_at: None,
+            state: BreakerState::Closed,
+        }
+    }
+
+    pub fn state(&self) -> BreakerState {
+        self.state
+    }
+
+    /// When an open breaker lets a trial through.
+    pub fn reopens_at(&self) -> Option<Instant> {
+        match (self.state, self.opened_at) {
+            (BreakerState::Open, Some(opened_at)) => Some(opened_at + self.open_for),
+            _ => None,
+        }
+    }
+
+    /// Whether actions may be sent to the resolver right now.
+    pub fn allows(&mut self, now: Instant) -> bool {
+        if let (BreakerState::Open, Some(opened_at)) = (self.state, self.opened_at) {
+            if now.duration_since(opened_at) >= self.open_for {
+                self.state = BreakerState::HalfOpen;
+            }
+        }
+        self.state != BreakerState::Open
+    }
+
+    pub fn record(&mut self, success: bool, now: Instant) {
+        match (self.state, success) {
+            (BreakerState::HalfOpen, true) => {
+                self.outcomes.clear();
+                self.state = BreakerState::Closed;
+                return;
+            }
+            (BreakerState::HalfOpen, false) => return self.open(now),
+            (BreakerState::Open, _) => return,
+            (BreakerState::Closed, _) => {}
+        }
+
+        self.outcomes.push_back(success);
+        if self.outcomes.len() > self.window {
+            self.outcomes.pop_front();
+        }
+        let failures = self.outcomes.iter().filter(|success| !**success).count();
+        if self.outcomes.len() == self.window
+            && failures as f64 / self.window as f64 >= self.failure_rate
+        {
+            self.open(now);
+        }
+    }
+
+    fn open(&mut self, now: Instant) {
+        self.outcomes.clear();
+        self.opened_at = Some(now);
+        self.state = BreakerState::Open;
+    }
+}
@@ -0,0 +1,68 @@
+use async_trait::async_trait;
+
+use crate::{
+    event_manip::{backend_command, run_command, BackendError, DnsInvalidate, InvalidateOutcome},
+    settings::{ContainerSettings, RndcSettings},
+    structs::RRsetKey,
+};
+
+/// BIND 9 through `rndc`. BIND has no per-type flush, so every type cached
+/// for the name goes at once.
+pub struct RndcInvalidator {
+    settings: RndcSettings,
+    container: Option<ContainerSettings>,
+}
+
+impl RndcInvalidator {
+    pub fn new(settings: &RndcSettings, container: Option<ContainerSettings>) -> Self {
+        RndcInvalidator {
+            settings: settings.clone(),
+            container,
+        }
+    }
+
+    /// `flushname` for a single name, `flushtree` for the name and
+    /// everything below it.
+    pub async fn flush(
+        &self,
+        domain_name: &str,
+        tree: bool,
+    ) -> Result<InvalidateOutcome, BackendError> {
+        let mut cmd = backend_command(self.container.as_ref(), &self.settings.binary);
+        cmd.arg("-s")
+            .arg(&self.settings.server)
+            .arg("-p")
+            .arg(self.settings.port.to_string())
+            .arg("-k")
+            .arg(&self.settings.key_file)
+            .arg(match tree {
+                true => "flushtree",
+                false => "flushname",
+            })
+            .arg(domain_name);
+        match run_command(cmd).await {
+            Ok(output) => Ok(InvalidateOutcome { output }),
+            // rndc reports every failure as `rndc: <what went wrong>` and
+            // exits with 1, the message is the useful part.
+            Err(BackendError::Exit { stderr, .. }) if stderr.starts_with("rndc: ") => Err(
+                BackendError::Protocol(stderr.trim_start_matches("rndc: ").trim().to_string()),
+            ),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[async_trait]
+impl DnsInvalidate for RndcInvalidator {
+    fn name(&self) -> &'static str {
+        "rndc"
+    }
+
+    async fn invalidate(&self, rrset: &RRsetKey) -> Result<InvalidateOutcome, BackendError> {
+        self.flush(&rrset.domain_name, false).await
+    }
+
+    async fn flush_zone(&self, suffix: &str) -> Result<InvalidateOutcome, BackendError> {
+        self.flush(suffix, true).await
+    }
+}
@@ -1,6 +1,10 @@
+use std::net::IpAddr;
+
 use config::{Config, File};
 use serde::{Deserialize, Serialize};
 
+use crate::structs::RecordType;
+
 pub fn settings() -> Settings {
     Config::builder()
         .add_source(File::with_name("Settings.toml"))
@@ -16,8 +20,457 @@ pub struct Settings {
     pub max_records_to_refresh_in_cycle: usize,
     pub min_ttl_to_keep_record: u32,
     pub max_ttl_to_keep_record: u32,
-    pub we_running_docker: bool,
     pub min_time_to_expire_to_purge: i64,
+    #[serde(default = "default_popularity_half_life")]
+    pub popularity_half_life: u64,
+    #[serde(default)]
+    pub min_popularity_to_refresh: f64,
+    #[serde(default = "default_max_tracked_records")]
+    pub max_tracked_records: usize,
+    #[serde(default = "default_max_records_per_parent_domain")]
+    pub max_records_per_parent_domain: usize,
+    /// Seconds a registrable domain stays flagged as abusive.
+    #[serde(default = "default_abusive_parent_domain_seconds")]
+    pub abusive_parent_domain_seconds: u64,
+    /// Path to Mozilla's `public_suffix_list.dat`. Without it, registrable
+    /// domains are taken to be the last two labels.
+    #[serde(default)]
+    pub public_suffix_list: Option<String>,
+    pub invalidator: InvalidatorKind,
+    #[serde(default)]
+    pub unbound_control: UnboundControlSettings,
+    pub repopulator: RepopulatorKind,
+    #[serde(default)]
+    pub dns_query: DnsQuerySettings,
+    #[serde(default)]
+    pub knot: KnotSettings,
+    #[serde(default)]
+    pub powerdns: PowerDnsSettings,
+    #[serde(default)]
+    pub rndc: RndcSettings,
+    /// Where the resolver answers queries, to verify refreshes on.
+    #[serde(default)]
+    pub verification_address: Option<String>,
+    /// Where process backends run when the resolver is containerized.
+    #[serde(default)]
+    pub container: Option<ContainerSettings>,
+    /// Resolvers managed by this instance. When empty, the backends above
+    /// make up a single resolver named `default`.
+    #[serde(default)]
+    pub resolvers: Vec<ResolverSettings>,
+    #[serde(default)]
+    pub fleet_dispatch: FleetDispatch,
+    #[serde(default)]
+    pub resilience: ResilienceSettings,
+    #[serde(default)]
+    pub refresh_strategy: RefreshStrategy,
+    /// Per-domain overrides, the first matching rule applies.
+    #[serde(default)]
+    pub policies: Vec<PolicySettings>,
+    #[serde(default)]
+    pub dry_run: DryRunSettings,
+    #[serde(default)]
+    pub audit: AuditSettings,
+    #[serde(default)]
+    pub verification: VerificationSettings,
+    #[serde(default)]
+    pub outage: OutageSettings,
+    #[serde(default)]
+    pub upstream_limits: UpstreamLimitSettings,
+    #[serde(default)]
+    pub api: ApiSettings,
+}
+
+fn default_popularity_half_life() -> u64 {
+    3600
+}
+
+fn default_max_tracked_records() -> usize {
+    100_000
+}
+
+fn default_max_records_per_parent_domain() -> usize {
+    1_000
+}
+
+fn default_abusive_parent_domain_seconds() -> u64 {
+    3600
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ApiSettings {
+    /// Bearer token the `POST` endpoints require, they refuse every request
+    /// without one.
+    pub token: Option<String>,
+}
+
+/// Refreshes per second allowed upstream, unlimited by default.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct UpstreamLimitSettings {
+    pub global_rate: Option<f64>,
+    /// Refreshes allowed at once, `global_rate` when unset.
+    pub global_burst: Option<f64>,
+    /// Per registrable domain.
+    pub domain_rate: Option<f64>,
+    pub domain_burst: Option<f64>,
+}
+
+/// Protects zones whose authoritative servers stop answering.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutageSettings {
+    pub enabled: bool,
+    /// Failed refreshes in a row after which a zone is no longer flushed.
+    pub failure_threshold: u32,
+    /// Seconds between probe queries to a zone in outage.
+    pub probe_interval: u64,
+    /// TTL resolvers give answers served past their expiry, unbound's
+    /// `serve-expired-reply-ttl`. A probe answered with no more than that may
+    /// not have reached the zone at all.
+    #[serde(default = "default_stale_reply_ttl")]
+    pub stale_reply_ttl: u32,
+}
+
+fn default_stale_reply_ttl() -> u32 {
+    30
+}
+
+impl Default for OutageSettings {
+    fn default() -> Self {
+        OutageSettings {
+            enabled: true,
+            failure_threshold: 3,
+            probe_interval: 60,
+            stale_reply_ttl: default_stale_reply_ttl(),
+        }
+    }
+}
+
+/// Checks after a refresh that the resolver holds a fresh copy.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VerificationSettings {
+    pub enabled: bool,
+    /// Cached TTL over the record's full TTL below which the refresh is
+    /// considered stale.
+    pub min_ttl_ratio: f64,
+    /// Times in a row a stale record is refreshed again before it is left
+    /// until it is captured again.
+    #[serde(default = "default_verification_max_retries")]
+    pub max_retries: u32,
+}
+
+fn default_verification_max_retries() -> u32 {
+    3
+}
+
+impl Default for VerificationSettings {
+    fn default() -> Self {
+        VerificationSettings {
+            enabled: false,
+            min_ttl_ratio: 0.9,
+            max_retries: default_verification_max_retries(),
+        }
+    }
+}
+
+/// Where every cache action is recorded, nowhere by default.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AuditSettings {
+    /// JSON lines file entries are appended to.
+    pub file: Option<String>,
+    /// Also store entries in the `cache_actions` table.
+    #[serde(default)]
+    pub store_in_database: bool,
+}
+
+/// Plan refreshes without touching the resolvers, see also `--dry-run`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DryRunSettings {
+    pub enabled: bool,
+    /// Also store planned actions in the `planned_actions` table.
+    pub store_in_database: bool,
+}
+
+impl Settings {
+    pub fn resolver_targets(&self) -> Vec<ResolverSettings> {
+        if !self.resolvers.is_empty() {
+            return self.resolvers.clone();
+        }
+        vec![ResolverSettings {
+            name: "default".to_string(),
+            addresses: vec![],
+            invalidator: self.invalidator,
+            repopulator: self.repopulator,
+            unbound_control: self.unbound_control.clone(),
+            dns_query: self.dns_query.clone(),
+            knot: self.knot.clone(),
+            powerdns: self.powerdns.clone(),
+            rndc: self.rndc.clone(),
+            verification_address: self.verification_address.clone(),
+            container: self.container.clone(),
+        }]
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum RefreshStrategy {
+    /// Flush the record, then query it again. Clients may miss in between.
+    #[default]
+    FlushThenQuery,
+    /// Only query, relying on the resolver's own prefetch or serve-expired.
+    QueryOnly,
+    /// Have the resolver fetch the RRset upstream and overwrite its copy.
+    Replace,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RefreshMode {
+    /// Let matching records expire.
+    Never,
+    /// Keep matching records warm whatever their TTL or popularity.
+    Always,
+}
+
+/// A refresh rule. Exactly one of `suffix`, `glob` and `regex` selects the
+/// names it applies to; the remaining fields override the global settings.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PolicySettings {
+    /// `example.com` matches the name itself and everything under it.
+    pub suffix: Option<String>,
+    /// `*` matches any run of characters, e.g. `*.tracker.*`.
+    pub glob: Option<String>,
+    pub regex: Option<String>,
+    /// Record types the rule applies to, all when empty.
+    #[serde(default)]
+    pub record_types: Vec<RecordType>,
+    pub refresh: Option<RefreshMode>,
+    /// Seconds before expiration to refresh, replaces
+    /// `min_time_to_expire_to_purge`.
+    pub lead_time_seconds: Option<i64>,
+    /// Lead time as a share of the record's TTL, 0 to 100.
+    pub lead_time_percent: Option<f64>,
+    pub min_popularity: Option<f64>,
+    /// Matching records refreshed per second at most.
+    pub max_refresh_rate: Option<f64>,
+    /// Name of the resolver matching records are refreshed on.
+    pub target: Option<String>,
+    pub strategy: Option<RefreshStrategy>,
+}
+
+/// Limits and retry policy for backend actions.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResilienceSettings {
+    /// Records being flushed or re-queried at once, across all resolvers.
+    pub max_in_flight: usize,
+    pub max_retries: u32,
+    pub retry_base_delay_ms: u64,
+    pub retry_max_delay_ms: u64,
+    /// Number of recent actions per resolver the failure rate is taken over.
+    pub breaker_window: usize,
+    pub breaker_failure_rate: f64,
+    pub breaker_open_seconds: u64,
+}
+
+impl Default for ResilienceSettings {
+    fn default() -> Self {
+        ResilienceSettings {
+            max_in_flight: 64,
+            max_retries: 3,
+            retry_base_delay_ms: 100,
+            retry_max_delay_ms: 5000,
+            breaker_window: 50,
+            breaker_failure_rate: 0.5,
+            breaker_open_seconds: 30,
+        }
+    }
+}
+
+/// One resolver of the fleet with its own backends and credentials.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResolverSettings {
+    pub name: String,
+    /// Addresses the resolver sends or receives DNS traffic on; captured
+    /// answers are matched against these.
+    #[serde(default)]
+    pub addresses: Vec<IpAddr>,
+    pub invalidator: InvalidatorKind,
+    pub repopulator: RepopulatorKind,
+    #[serde(default)]
+    pub unbound_control: UnboundControlSettings,
+    #[serde(default)]
+    pub dns_query: DnsQuerySettings,
+    #[serde(default)]
+    pub knot: KnotSettings,
+    #[serde(default)]
+    pub powerdns: PowerDnsSettings,
+    #[serde(default)]
+    pub rndc: RndcSettings,
+    /// Needed with verification unless the repopulator queries the resolver
+    /// itself, as `dns_query` and `powerdns` do.
+    #[serde(default)]
+    pub verification_address: Option<String>,
+    #[serde(default)]
+    pub container: Option<ContainerSettings>,
+}
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FleetDispatch {
+    /// Act on the resolvers whose addresses match the captured answer, or
+    /// on all of them when none does.
+    #[default]
+    Matching,
+    /// Act on every resolver regardless of where the answer was seen.
+    All,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ContainerRuntime {
+    Docker,
+    Podman,
+    Nerdctl,
+}
+
+impl ContainerRuntime {
+    pub fn binary(&self) -> &'static str {
+        match self {
+            ContainerRuntime::Docker => "docker",
+            ContainerRuntime::Podman => "podman",
+            ContainerRuntime::Nerdctl => "nerdctl",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ContainerSettings {
+    pub runtime: ContainerRuntime,
+    pub name: String,
+    /// Extra `exec` flags, e.g. `["--user", "unbound"]`. Never needs `-t`.
+    #[serde(default)]
+    pub exec_options: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum InvalidatorKind {
+    /// Spawns `unbound-control`, inside `container` when configured.
+    UnboundControl,
+    /// Talks to unbound's remote control port over TLS.
+    UnboundTls,
+    /// `cache.clear()` through the kresd control socket (Knot Resolver 5).
+    KnotControl,
+    /// `/cache/clear` on the management HTTP API (Knot Resolver 6).
+    KnotManager,
+    /// `cache/flush` on the PowerDNS Recursor REST API.
+    PowerDns,
+    /// Spawns `rndc flushname`, inside `container` when configured.
+    Rndc,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RndcSettings {
+    pub binary: String,
+    pub server: String,
+    pub port: u16,
+    pub key_file: String,
+}
+
+impl Default for RndcSettings {
+    fn default() -> Self {
+        RndcSettings {
+            binary: "rndc".to_string(),
+            server: "127.0.0.1".to_string(),
+            port: 953,
+            key_file: "/etc/bind/rndc.key".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RepopulatorKind {
+    /// Spawns `dig`, inside `container` when configured.
+    Dig,
+    /// Sends the query to `dns_query.resolver` itself.
+    DnsQuery,
+    /// `resolve()` through the kresd control socket (Knot Resolver 5).
+    KnotControl,
+    /// Sends the query to `powerdns.dns_address` itself.
+    PowerDns,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PowerDnsSettings {
+    pub api_url: String,
+    pub api_key: String,
+    pub server_id: String,
+    pub dns_address: String,
+}
+
+impl Default for PowerDnsSettings {
+    fn default() -> Self {
+        PowerDnsSettings {
+            api_url: "http://127.0.0.1:8082".to_string(),
+            api_key: String::new(),
+            server_id: "localhost".to_string(),
+            dns_address: "127.0.0.1:53".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KnotSettings {
+    pub control_socket: String,
+    pub manager_url: String,
+}
+
+impl Default for KnotSettings {
+    fn default() -> Self {
+        KnotSettings {
+            control_socket: "/run/knot-resolver/control/1".to_string(),
+            manager_url: "http://127.0.0.1:5000".to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DnsQuerySettings {
+    pub resolver: String,
+    pub timeout_ms: u64,
+}
+
+impl Default for DnsQuerySettings {
+    fn default() -> Self {
+        DnsQuerySettings {
+            resolver: "127.0.0.1:53".to_string(),
+            timeout_ms: 2000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UnboundControlSettings {
+    pub address: String,
+    pub server_name: String,
+    pub server_cert: String,
+    pub client_cert: String,
+    pub client_key: String,
+    pub max_connections: usize,
+}
+
+impl Default for UnboundControlSettings {
+    fn default() -> Self {
+        UnboundControlSettings {
+            address: "127.0.0.1:8953".to_string(),
+            server_name: "unbound".to_string(),
+            server_cert: "/etc/unbound/unbound_server.pem".to_string(),
+            client_cert: "/etc/unbound/unbound_control.pem".to_string(),
+            client_key: "/etc/unbound/unbound_control.key".to_string(),
+            max_connections: 16,
+        }
+    }
 }
 
 impl Default for Settings {
@@ -27,8 +480,33 @@ impl Default for Settings {
             min_ttl_to_keep_record: 30,
             max_ttl_to_keep_record: 3600,
             max_records_to_refresh_in_cycle: 100,
-            we_running_docker: false,
+            container: None,
             min_time_to_expire_to_purge: 300,
+            popularity_half_life: default_popularity_half_life(),
+            min_popularity_to_refresh: 0.0,
+            max_tracked_records: default_max_tracked_records(),
+            max_records_per_parent_domain: default_max_records_per_parent_domain(),
+            abusive_parent_domain_seconds: default_abusive_parent_domain_seconds(),
+            public_suffix_list: None,
+            invalidator: InvalidatorKind::UnboundControl,
+            unbound_control: UnboundControlSettings::default(),
+            repopulator: RepopulatorKind::Dig,
+            dns_query: DnsQuerySettings::default(),
+            knot: KnotSettings::default(),
+            powerdns: PowerDnsSettings::default(),
+            rndc: RndcSettings::default(),
+            verification_address: None,
+            resolvers: vec![],
+            fleet_dispatch: FleetDispatch::default(),
+            resilience: ResilienceSettings::default(),
+            refresh_strategy: RefreshStrategy::default(),
+            policies: vec![],
+            dry_run: DryRunSettings::default(),
+            audit: AuditSettings::default(),
+            verification: VerificationSettings::default(),
+            outage: OutageSettings::default(),
+            upstream_limits: UpstreamLimitSettings::default(),
+            api: ApiSettings::default(),
         }
     }
 }
@@ -1,18 +1,29 @@
-use std::{collections::BinaryHeap, sync::Arc};
+use std::{net::IpAddr, str::FromStr, sync::Arc};
 
 use chrono::{DateTime, TimeDelta, Utc};
-use dns_parser::{Class, RData, ResourceRecord};
+use dns_parser::{Class, QueryType, RData, ResourceRecord};
 use serde::{Deserialize, Serialize};
 use sqlx::prelude::{FromRow, Type};
 use tokio::sync::RwLock;
 
-use crate::settings::{self};
+use crate::{
+    expiry_queue::ExpiryQueue,
+    settings::{self},
+};
 
-pub type DnsResponse = Vec<DnsAnswer>;
+/// The answers of a captured response, with what tells the query it answers
+/// apart.
+#[derive(Debug)]
+pub struct DnsResponse {
+    pub id: u16,
+    /// Port the query was sent from.
+    pub destination_port: Option<u16>,
+    pub answers: Vec<DnsAnswer>,
+}
 
-pub type Universe = Arc<RwLock<BinaryHeap<DnsAnswer>>>;
+pub type Universe = Arc<RwLock<ExpiryQueue>>;
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash, Type)]
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash, Type)]
 pub enum RecordType {
     A = 1,
     AAAA = 2,
@@ -29,6 +40,20 @@ pub enum Cls {
     CH = 3,
     HS = 4,
 }
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct RRsetKey {
+    pub domain_name: String,
+    pub record_type: RecordType,
+}
+
+/// When an RRset is due for a refresh, compared field by field: due time,
+/// name, type.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct ScheduleKey {
+    pub due: DateTime<Utc>,
+    pub rrset: RRsetKey,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash, FromRow)]
 pub struct DnsAnswer {
     pub domain_name: String,
@@ -36,6 +61,11 @@ pub struct DnsAnswer {
     pub cls: Cls,
     pub record_type: RecordType,
     pub read_from_buffer_ts: DateTime<Utc>,
+    pub alias_target: Option<String>,
+    /// Endpoints of the packet the answer came in, used to tell which
+    /// resolver of the fleet served it.
+    pub source_address: Option<IpAddr>,
+    pub destination_address: Option<IpAddr>,
 }
 
 impl RecordType {
@@ -49,6 +79,34 @@ impl RecordType {
             RecordType::Other => "any",
         }
     }
+
+    pub fn query_type(&self) -> QueryType {
+        match self {
+            RecordType::A => QueryType::A,
+            RecordType::AAAA => QueryType::AAAA,
+            RecordType::CNAME => QueryType::CNAME,
+            RecordType::MX => QueryType::MX,
+            RecordType::TXT => QueryType::TXT,
+            RecordType::Other => QueryType::All,
+        }
+    }
+}
+
+/// Parses the command line form, in any case.
+impl FromStr for RecordType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "a" => Ok(RecordType::A),
+            "aaaa" => Ok(RecordType::AAAA),
+            "cname" => Ok(RecordType::CNAME),
+            "mx" => Ok(RecordType::MX),
+            "txt" => Ok(RecordType::TXT),
+            "any" => Ok(RecordType::Other),
+            _ => Err(format!("unsupported record type {}", s)),
+        }
+    }
 }
 
 impl From<RData<'_>> for RecordType {
@@ -75,15 +133,41 @@ impl From<Class> for Cls {
     }
 }
 
-impl From<(ResourceRecord<'_>, DateTime<Utc>)> for DnsAnswer {
-    fn from(t: (ResourceRecord<'_>, DateTime<Utc>)) -> Self {
-        DnsAnswer {
-            domain_name: t.0.name.to_string().clone(),
+/// Names end up in backend commands, so only what DNS names are made of is
+/// let through.
+pub fn valid_domain_name(domain_name: &str) -> bool {
+    !domain_name.is_empty()
+        && domain_name.len() <= 253
+        && domain_name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+}
+
+/// Fails on owner names that are not hostnames. Alias targets that are not
+/// are dropped, so they are never followed.
+impl TryFrom<(ResourceRecord<'_>, DateTime<Utc>)> for DnsAnswer {
+    type Error = String;
+
+    fn try_from(t: (ResourceRecord<'_>, DateTime<Utc>)) -> Result<Self, Self::Error> {
+        let domain_name = t.0.name.to_string();
+        if !valid_domain_name(&domain_name) {
+            return Err(format!("invalid owner name {:?}", domain_name));
+        }
+        let alias_target = match &t.0.data {
+            RData::CNAME(target) => Some(target.0.to_string()),
+            _ => None,
+        }
+        .filter(|target| valid_domain_name(target));
+        Ok(DnsAnswer {
+            domain_name,
             ttl: t.0.ttl,
             cls: t.0.cls.into(),
             record_type: t.0.data.into(),
             read_from_buffer_ts: t.1,
-        }
+            alias_target,
+            source_address: None,
+            destination_address: None,
+        })
     }
 }
 
@@ -94,27 +178,33 @@ impl DnsAnswer {
             .unwrap_or(DateTime::<Utc>::MAX_UTC)
     }
 
-    pub fn has_reasonable_ttl(&self) -> bool {
-        self.ttl > settings::settings().min_ttl_to_keep_record
-            && self.ttl < settings::settings().max_ttl_to_keep_record
+    pub fn rrset_key(&self) -> RRsetKey {
+        RRsetKey {
+            domain_name: self.domain_name.clone(),
+            record_type: self.record_type.clone(),
+        }
     }
-}
 
-impl PartialOrd for DnsAnswer {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(self.cmp(other))
+    /// Due `lead_time` before the answer expires.
+    pub fn schedule_key(&self, lead_time: TimeDelta) -> ScheduleKey {
+        ScheduleKey {
+            due: self
+                .expiration_time()
+                .checked_sub_signed(lead_time)
+                .unwrap_or(DateTime::<Utc>::MIN_UTC),
+            rrset: self.rrset_key(),
+        }
     }
-}
 
-impl Ord for DnsAnswer {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        if self.expiration_time() == other.expiration_time() {
-            std::cmp::Ordering::Equal
-        } else {
-            match self.expiration_time() > other.expiration_time() {
-                true => std::cmp::Ordering::Less,
-                false => std::cmp::Ordering::Greater,
-            }
-        }
+    /// Both ends of the packet the answer came in.
+    pub fn endpoints(&self) -> impl Iterator<Item = IpAddr> {
+        [self.source_address, self.destination_address]
+            .into_iter()
+            .flatten()
+    }
+
+    pub fn has_reasonable_ttl(&self) -> bool {
+        self.ttl > settings::settings().min_ttl_to_keep_record
+            && self.ttl < settings::settings().max_ttl_to_keep_record
     }
 }
@@ -0,0 +1,60 @@
+use std::time::{Duration, Instant};
+
+/// Allows `rate` actions per second on average and at most `capacity` at
+/// once.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    /// Starts full.
+    pub fn new(rate: f64, capacity: f64) -> Self {
+        let capacity = capacity.max(1.0);
+        TokenBucket {
+            rate: rate.max(f64::MIN_POSITIVE),
+            capacity,
+            tokens: capacity,
+            updated: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.updated = now;
+    }
+
+    /// Whole tokens available right now.
+    pub fn available(&mut self, now: Instant) -> usize {
+        self.refill(now);
+        self.tokens as usize
+    }
+
+    /// Whether `tokens` can be taken right now. Asking for more than the
+    /// bucket holds waits for it to be full instead of forever.
+    pub fn has(&mut self, tokens: usize, now: Instant) -> bool {
+        self.available(now) >= tokens.min(self.capacity as usize).max(1)
+    }
+
+    pub fn is_full(&mut self, now: Instant) -> bool {
+        self.refill(now);
+        self.tokens >= self.capacity
+    }
+
+    pub fn take(&mut self, tokens: usize) {
+        self.tokens -= tokens as f64;
+    }
+
+    /// How long until a whole token is available.
+    pub fn wait_time(&mut self, now: Instant) -> Duration {
+        self.refill(now);
+        match self.tokens >= 1.0 {
+            true => Duration::ZERO,
+            false => Duration::from_secs_f64((1.0 - self.tokens) / self.rate),
+        }
+    }
+}
@@ -0,0 +1,108 @@
+use std::fs;
+
+use anyhow::Context as _;
+use async_trait::async_trait;
+use native_tls::{Certificate, Identity};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    sync::Semaphore,
+};
+use tokio_native_tls::TlsConnector;
+
+use crate::{
+    event_manip::{BackendError, DnsInvalidate, InvalidateOutcome},
+    settings::UnboundControlSettings,
+    structs::RRsetKey,
+};
+
+// Every request on the remote control port starts with the protocol version.
+const PROTOCOL_HEADER: &str = "UBCT1 ";
+
+/// Speaks unbound's remote control protocol directly, the way
+/// `unbound-control` does, instead of forking it for every record.
+///
+/// Unbound serves a single command per connection and closes it afterwards,
+/// so what is reused across commands is the TLS setup (certificates are read
+/// and parsed once). Concurrent commands are capped by `max_connections`.
+pub struct UnboundControlClient {
+    address: String,
+    server_name: String,
+    connector: TlsConnector,
+    connections: Semaphore,
+}
+
+impl UnboundControlClient {
+    pub fn from_settings(settings: &UnboundControlSettings) -> anyhow::Result<Self> {
+        let server_cert = fs::read(&settings.server_cert)
+            .with_context(|| format!("reading {}", settings.server_cert))?;
+        let client_cert = fs::read(&settings.client_cert)
+            .with_context(|| format!("reading {}", settings.client_cert))?;
+        let client_key = fs::read(&settings.client_key)
+            .with_context(|| format!("reading {}", settings.client_key))?;
+
+        let connector = native_tls::TlsConnector::builder()
+            .add_root_certificate(Certificate::from_pem(&server_cert)?)
+            .identity(Identity::from_pkcs8(&client_cert, &client_key)?)
+            .build()?;
+
+        Ok(UnboundControlClient {
+            address: settings.address.clone(),
+            server_name: settings.server_name.clone(),
+            connector: TlsConnector::from(connector),
+            connections: Semaphore::new(settings.max_connections.max(1)),
+        })
+    }
+
+    /// Runs one remote control command and returns its output. Unbound
+    /// reports failures in-band, as output starting with `error`.
+    pub async fn execute(&self, command: &str) -> Result<String, BackendError> {
+        let _permit = self
+            .connections
+            .acquire()
+            .await
+            .map_err(|e| BackendError::Protocol(e.to_string()))?;
+
+        let stream = TcpStream::connect(&self.address).await?;
+        let mut stream = self
+            .connector
+            .connect(&self.server_name, stream)
+            .await
+            .map_err(|e| BackendError::Protocol(format!("TLS handshake failed: {}", e)))?;
+
+        stream
+            .write_all(format!("{}{}\n", PROTOCOL_HEADER, command).as_bytes())
+            .await?;
+        stream.flush().await?;
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await?;
+        match response.starts_with("error") {
+            true => Err(BackendError::Protocol(response.trim().to_string())),
+            false => Ok(response),
+        }
+    }
+}
+
+#[async_trait]
+impl DnsInvalidate for UnboundControlClient {
+    fn name(&self) -> &'static str {
+        "unbound_tls"
+    }
+
+    async fn invalidate(&self, rrset: &RRsetKey) -> Result<InvalidateOutcome, BackendError> {
+        let output = self
+            .execute(&format!(
+                "flush_type {} {}",
+                rrset.domain_name,
+                rrset.record_type.form_for_command_line_arg()
+            ))
+            .await?;
+        Ok(InvalidateOutcome { output })
+    }
+
+    async fn flush_zone(&self, suffix: &str) -> Result<InvalidateOutcome, BackendError> {
+        let output = self.execute(&format!("flush_zone {}", suffix)).await?;
+        Ok(InvalidateOutcome { output })
+    }
+}
@@ -0,0 +1,96 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use anyhow::bail;
+
+use crate::{
+    public_suffix::{self, registrable_domain},
+    settings::UpstreamLimitSettings,
+    token_bucket::TokenBucket,
+};
+
+/// Which limit held a refresh back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Throttle {
+    Global,
+    Domain,
+    Policy,
+}
+
+impl Throttle {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Throttle::Global => "global",
+            Throttle::Domain => "domain",
+            Throttle::Policy => "policy",
+        }
+    }
+}
+
+/// Caps the refreshes that make resolvers query upstream, in total and per
+/// registrable domain, since the names under one registrable domain usually
+/// share nameservers.
+#[derive(Debug)]
+pub struct UpstreamLimiter {
+    global: Option<TokenBucket>,
+    domain_rate: Option<(f64, f64)>,
+    domains: HashMap<String, TokenBucket>,
+}
+
+impl UpstreamLimiter {
+    /// Per-domain limits need a public suffix list, without one every
+    /// `co.uk` site would share a bucket.
+    pub fn from_settings(settings: &UpstreamLimitSettings) -> anyhow::Result<Self> {
+        if settings.domain_rate.is_some() && !public_suffix::is_loaded() {
+            bail!("domain_rate needs public_suffix_list to tell registrable domains apart");
+        }
+        Ok(UpstreamLimiter {
+            global: settings
+                .global_rate
+                .map(|rate| TokenBucket::new(rate, settings.global_burst.unwrap_or(rate))),
+            domain_rate: settings
+                .domain_rate
+                .map(|rate| (rate, settings.domain_burst.unwrap_or(rate))),
+            domains: HashMap::new(),
+        })
+    }
+
+    /// Takes `tokens` for a refresh of `domain_name` from every limit, or
+    /// none of them. `Err` holds the limit that is exhausted and how long
+    /// until it has a token.
+    pub fn try_acquire(
+        &mut self,
+        domain_name: &str,
+        tokens: usize,
+        now: Instant,
+    ) -> Result<(), (Throttle, Duration)> {
+        if let Some(global) = self.global.as_mut() {
+            if !global.has(tokens, now) {
+                return Err((Throttle::Global, global.wait_time(now)));
+            }
+        }
+        if let Some((rate, burst)) = self.domain_rate {
+            let domain = registrable_domain(domain_name);
+            let bucket = self
+                .domains
+                .entry(domain)
+                .or_insert_with(|| TokenBucket::new(rate, burst));
+            if !bucket.has(tokens, now) {
+                return Err((Throttle::Domain, bucket.wait_time(now)));
+            }
+            bucket.take(tokens);
+        }
+        if let Some(global) = self.global.as_mut() {
+            global.take(tokens);
+        }
+        Ok(())
+    }
+
+    /// Drops the buckets of domains that have not been refreshed for long
+    /// enough to be full again.
+    pub fn forget_idle(&mut self, now: Instant) {
+        self.domains.retain(|_, bucket| !bucket.is_full(now));
+    }
+}
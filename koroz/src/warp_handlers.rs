@@ -1,13 +1,29 @@
 use std::convert::Infallible;
 
 use prometheus::{Encoder, TextEncoder};
+use serde::Serialize;
 use warp::http::StatusCode;
 use warp::{
     reply::{self, Reply},
     Filter,
 };
 
-use crate::structs::Universe;
+use crate::chains::{AliasChain, Chains};
+use crate::control::PurgerControl;
+use crate::dry_run::PlannedActions;
+use crate::event_manip::Dispatch;
+use crate::manual::{self, ManualOutcome};
+use crate::policy::Policies;
+use crate::settings::settings;
+use crate::structs::{valid_domain_name, DnsAnswer, RRsetKey, RecordType, Universe};
+use crate::API_REQUESTS_COUNTER_VEC;
+
+#[derive(Serialize)]
+struct UniverseEntry<'a> {
+    #[serde(flatten)]
+    answer: &'a DnsAnswer,
+    chains: Vec<AliasChain>,
+}
 
 pub fn with_universe(
     universe: Universe,
@@ -15,12 +31,220 @@ pub fn with_universe(
     warp::any().map(move || universe.clone())
 }
 
-pub async fn get_universe(universe: Universe) -> Result<impl Reply, warp::Rejection> {
+pub fn with_alias_chains(
+    alias_chains: Chains,
+) -> impl Filter<Extract = (Chains,), Error = Infallible> + Clone {
+    warp::any().map(move || alias_chains.clone())
+}
+
+pub async fn get_universe(
+    universe: Universe,
+    alias_chains: Chains,
+) -> Result<impl Reply, warp::Rejection> {
     let universe = universe.read().await;
+    let alias_chains = alias_chains.read().await;
+    let entries: Vec<UniverseEntry> = universe
+        .iter()
+        .map(|answer| UniverseEntry {
+            answer,
+            chains: alias_chains.chains_through(&answer.domain_name),
+        })
+        .collect();
+
+    std::result::Result::Ok(reply::with_status(reply::json(&entries), StatusCode::OK))
+}
+
+pub fn with_planned_actions(
+    planned_actions: PlannedActions,
+) -> impl Filter<Extract = (PlannedActions,), Error = Infallible> + Clone {
+    warp::any().map(move || planned_actions.clone())
+}
+
+pub async fn get_planned_actions(
+    planned_actions: PlannedActions,
+) -> Result<impl Reply, warp::Rejection> {
+    let planned_actions = planned_actions.read().await;
+    let entries: Vec<_> = planned_actions.iter().collect();
+
+    std::result::Result::Ok(reply::with_status(reply::json(&entries), StatusCode::OK))
+}
+
+/// What the manual action endpoints act through.
+#[derive(Clone)]
+pub struct ManualActions {
+    pub dispatch: Dispatch,
+    pub policies: Policies,
+}
+
+pub fn with_manual_actions(
+    manual_actions: ManualActions,
+) -> impl Filter<Extract = (ManualActions,), Error = Infallible> + Clone {
+    warp::any().map(move || manual_actions.clone())
+}
+
+fn authorized(authorization: &Option<String>) -> bool {
+    match settings().api.token {
+        Some(token) => authorization.as_deref() == Some(format!("Bearer {}", token).as_str()),
+        None => false,
+    }
+}
+
+fn api_reply(
+    endpoint: &str,
+    status: StatusCode,
+    body: &impl Serialize,
+) -> reply::WithStatus<reply::Json> {
+    API_REQUESTS_COUNTER_VEC
+        .with_label_values(&[endpoint, status.as_str()])
+        .inc();
+    reply::with_status(reply::json(body), status)
+}
+
+fn api_error(endpoint: &str, status: StatusCode, error: String) -> reply::WithStatus<reply::Json> {
+    api_reply(endpoint, status, &serde_json::json!({ "error": error }))
+}
+
+fn outcomes_reply(endpoint: &str, outcomes: Vec<ManualOutcome>) -> reply::WithStatus<reply::Json> {
+    let status = match outcomes.iter().any(ManualOutcome::failed) {
+        true => StatusCode::BAD_GATEWAY,
+        false => StatusCode::OK,
+    };
+    api_reply(endpoint, status, &outcomes)
+}
+
+/// Checks the request and turns the path into an RRset.
+fn manual_record(
+    endpoint: &str,
+    authorization: &Option<String>,
+    domain_name: String,
+    record_type: &str,
+) -> Result<RRsetKey, reply::WithStatus<reply::Json>> {
+    if !authorized(authorization) {
+        return Err(api_error(
+            endpoint,
+            StatusCode::UNAUTHORIZED,
+            "missing or wrong bearer token".to_string(),
+        ));
+    }
+    if !valid_domain_name(&domain_name) {
+        return Err(api_error(
+            endpoint,
+            StatusCode::BAD_REQUEST,
+            format!("invalid domain name {}", domain_name),
+        ));
+    }
+    let record_type = record_type
+        .parse::<RecordType>()
+        .map_err(|e| api_error(endpoint, StatusCode::BAD_REQUEST, e))?;
+    Ok(RRsetKey {
+        domain_name,
+        record_type,
+    })
+}
+
+pub async fn invalidate_record(
+    domain_name: String,
+    record_type: String,
+    authorization: Option<String>,
+    manual_actions: ManualActions,
+) -> Result<impl Reply, warp::Rejection> {
+    let endpoint = "invalidate";
+    let rrset = match manual_record(endpoint, &authorization, domain_name, &record_type) {
+        Ok(rrset) => rrset,
+        Err(reply) => return Ok(reply),
+    };
+    let outcomes = manual::invalidate(&manual_actions.dispatch, &rrset).await;
+
+    std::result::Result::Ok(outcomes_reply(endpoint, outcomes))
+}
+
+pub async fn refresh_record(
+    domain_name: String,
+    record_type: String,
+    authorization: Option<String>,
+    manual_actions: ManualActions,
+) -> Result<impl Reply, warp::Rejection> {
+    let endpoint = "refresh";
+    let rrset = match manual_record(endpoint, &authorization, domain_name, &record_type) {
+        Ok(rrset) => rrset,
+        Err(reply) => return Ok(reply),
+    };
+    let strategy = manual_actions
+        .policies
+        .matching(&rrset)
+        .and_then(|policy| policy.strategy)
+        .unwrap_or(settings().refresh_strategy);
+    let outcomes = manual::refresh(&manual_actions.dispatch, &rrset, strategy).await;
+
+    std::result::Result::Ok(outcomes_reply(endpoint, outcomes))
+}
 
+pub async fn flush_zone(
+    suffix: String,
+    authorization: Option<String>,
+    manual_actions: ManualActions,
+) -> Result<impl Reply, warp::Rejection> {
+    let endpoint = "flush_zone";
+    if !authorized(&authorization) {
+        return Ok(api_error(
+            endpoint,
+            StatusCode::UNAUTHORIZED,
+            "missing or wrong bearer token".to_string(),
+        ));
+    }
+    if !valid_domain_name(&suffix) {
+        return Ok(api_error(
+            endpoint,
+            StatusCode::BAD_REQUEST,
+            format!("invalid zone {}", suffix),
+        ));
+    }
+    let outcomes = manual::flush_zone(&manual_actions.dispatch, &suffix).await;
+
+    std::result::Result::Ok(outcomes_reply(endpoint, outcomes))
+}
+
+pub fn with_purger_control(
+    control: PurgerControl,
+) -> impl Filter<Extract = (PurgerControl,), Error = Infallible> + Clone {
+    warp::any().map(move || control.clone())
+}
+
+pub async fn get_purger_state(control: PurgerControl) -> Result<impl Reply, warp::Rejection> {
     std::result::Result::Ok(reply::with_status(
-        reply::json(&universe.clone()),
+        reply::json(&serde_json::json!({ "state": control.state() })),
+        StatusCode::OK,
+    ))
+}
+
+pub async fn control_purger(
+    command: String,
+    authorization: Option<String>,
+    control: PurgerControl,
+) -> Result<impl Reply, warp::Rejection> {
+    let endpoint = match command.as_str() {
+        "pause" => "pause",
+        "resume" => "resume",
+        "drain" => "drain",
+        _ => return Err(warp::reject::not_found()),
+    };
+    if !authorized(&authorization) {
+        return Ok(api_error(
+            endpoint,
+            StatusCode::UNAUTHORIZED,
+            "missing or wrong bearer token".to_string(),
+        ));
+    }
+    match endpoint {
+        "pause" => control.pause(),
+        "resume" => control.resume(),
+        _ => control.drain(),
+    }
+
+    std::result::Result::Ok(api_reply(
+        endpoint,
         StatusCode::OK,
+        &serde_json::json!({ "state": control.state() }),
     ))
 }
 